@@ -0,0 +1,214 @@
+use std::{fs, net::IpAddr, path::PathBuf, str::FromStr, time::Duration};
+
+use crate::{error::Error, log::LogLevel, Limits};
+
+pub const USAGE: &str = "\
+Usage: real_db [options]
+
+Options:
+  --config <file>             Read settings from a config file
+  --bind <address>            Address to listen on (default 127.0.0.1)
+  --port <port>               Port to listen on (default 1234)
+  --workers <count>           Threads handling requests (default 4)
+  --data-dir <dir>            Directory holding the log and snapshots (default data)
+  --max-request-size <bytes>  Largest request body accepted (default 1048576)
+  --snapshot-interval <secs>  Seconds between snapshots, 0 disables them (default 300)
+  --shutdown-timeout <secs>   Seconds to wait for running queries on shutdown (default 10)
+  --max-instructions <count>  Operations a query may run, 0 for no limit (default 100000000)
+  --query-timeout <secs>      Seconds a query may run, 0 for no limit (default 30)
+  --max-expand-depth <depth>  Highest Expand depth, 0 for no limit (default 32)
+  --log-level <level>         off, error, warn, info or debug (default info)
+  -h, --help                  Print this message
+";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    pub bind: IpAddr,
+    pub port: u16,
+    // Threads handling requests
+    pub workers: usize,
+    pub data_dir: PathBuf,
+    // Largest request body accepted, in bytes
+    pub max_request_size: usize,
+    // Seconds between two periodic snapshots, 0 disables them
+    pub snapshot_interval: u64,
+    // Seconds running queries get to finish on shutdown
+    pub shutdown_timeout: u64,
+    // Operations one query may run, 0 for no limit
+    pub max_instructions: u64,
+    // Seconds one query may run, 0 for no limit
+    pub query_timeout: u64,
+    // Highest depth `Expand` accepts, 0 for no limit
+    pub max_expand_depth: u64,
+    pub log_level: LogLevel,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            bind: IpAddr::from([127, 0, 0, 1]),
+            port: 1234,
+            workers: 4,
+            data_dir: PathBuf::from("data"),
+            max_request_size: 1024 * 1024,
+            snapshot_interval: 300,
+            shutdown_timeout: 10,
+            max_instructions: 100_000_000,
+            query_timeout: 30,
+            max_expand_depth: 32,
+            log_level: LogLevel::Info,
+        }
+    }
+}
+
+impl ServerConfig {
+    // Reads the command line arguments, without the program name.
+    // Flags override the config file, which overrides the defaults
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<ServerConfig, Error> {
+        let mut config_file = None;
+        let mut flags = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let flag = match arg.strip_prefix("--") {
+                Some(val) => val,
+                None => return Err(Error::Config(format!("Unexpected argument `{}`", arg))),
+            };
+
+            // Both `--port 80` and `--port=80` are accepted
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name.to_owned(), value.to_owned()),
+                None => match args.next() {
+                    Some(value) => (flag.to_owned(), value),
+                    None => return Err(Error::Config(format!("Missing value for --{}", flag))),
+                },
+            };
+
+            if name == "config" {
+                config_file = Some(value);
+            } else {
+                flags.push((name, value));
+            }
+        }
+
+        let mut config = ServerConfig::default();
+
+        if let Some(path) = config_file {
+            let contents = match fs::read_to_string(&path) {
+                Ok(val) => val,
+                Err(err) => {
+                    return Err(Error::io(
+                        &format!("Unable to read config file {}", path),
+                        err,
+                    ))
+                }
+            };
+            if let Err(message) = config.read_file(&contents) {
+                return Err(Error::Config(format!("{}: {}", path, message)));
+            }
+        }
+
+        for (name, value) in flags {
+            if let Err(message) = config.set(&name.replace('-', "_"), &value) {
+                return Err(Error::Config(format!("--{}: {}", name, message)));
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    // One `key = value` per line, values may be double quoted
+    // and `#` starts a comment like in TOML
+    fn read_file(&mut self, contents: &str) -> Result<(), String> {
+        for (i, line) in contents.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some(val) => val,
+                None => return Err(format!("line {}: expected `key = value`", i + 1)),
+            };
+
+            let result = unquote(value.trim()).and_then(|value| self.set(key.trim(), value));
+            if let Err(message) = result {
+                return Err(format!("line {}: {}", i + 1, message));
+            }
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "bind" => self.bind = parse(key, value)?,
+            "port" => self.port = parse(key, value)?,
+            "workers" => self.workers = parse(key, value)?,
+            "data_dir" => self.data_dir = PathBuf::from(value),
+            "max_request_size" => self.max_request_size = parse(key, value)?,
+            "snapshot_interval" => self.snapshot_interval = parse(key, value)?,
+            "shutdown_timeout" => self.shutdown_timeout = parse(key, value)?,
+            "max_instructions" => self.max_instructions = parse(key, value)?,
+            "query_timeout" => self.query_timeout = parse(key, value)?,
+            "max_expand_depth" => self.max_expand_depth = parse(key, value)?,
+            "log_level" => self.log_level = value.parse()?,
+            _ => return Err(format!("Unknown setting `{}`", key)),
+        }
+        Ok(())
+    }
+
+    // Limits every query runs under
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_instructions: self.max_instructions,
+            timeout: Duration::from_secs(self.query_timeout),
+            max_expand_depth: self.max_expand_depth,
+        }
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.workers == 0 {
+            return Err(Error::Config("workers must be at least 1".to_owned()));
+        }
+        if self.max_request_size == 0 {
+            return Err(Error::Config(
+                "max_request_size must be at least 1".to_owned(),
+            ));
+        }
+        if self.data_dir.as_os_str().is_empty() {
+            return Err(Error::Config("data_dir can't be empty".to_owned()));
+        }
+        Ok(())
+    }
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    match value.parse() {
+        Ok(val) => Ok(val),
+        Err(_) => Err(format!("Invalid value `{}` for {}", value, key)),
+    }
+}
+
+// Drops a `#` comment, unless the `#` is inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, ch) in line.char_indices() {
+        match ch {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn unquote(value: &str) -> Result<&str, String> {
+    match value.strip_prefix('"') {
+        Some(rest) => match rest.strip_suffix('"') {
+            Some(val) if !val.contains('"') => Ok(val),
+            _ => Err(format!("Unterminated string `{}`", value)),
+        },
+        None => Ok(value),
+    }
+}
@@ -0,0 +1,275 @@
+use std::{fmt, io};
+
+use crate::http::HttpError;
+use crate::query::Span;
+use crate::RecordId;
+
+// Runtime errors carry the span of the operation that
+// raised them, it is filled in by `execute_program`
+#[derive(Debug)]
+pub enum Error {
+    // The query could not be parsed
+    ParseError {
+        message: String,
+        span: Span,
+    },
+    // A value on the stack has the wrong type for the operation
+    TypeError {
+        message: String,
+        span: Option<Span>,
+    },
+    // The operation needs more values than the stack holds
+    StackUnderflow {
+        needed: usize,
+        found: usize,
+        span: Option<Span>,
+    },
+    TableNotFound {
+        table_name: String,
+        span: Option<Span>,
+    },
+    RecordNotFound {
+        record_id: RecordId,
+        span: Option<Span>,
+    },
+    // The record was changed since the version a write expected
+    Conflict {
+        record_id: RecordId,
+        expected: u64,
+        found: u64,
+        span: Option<Span>,
+    },
+    // The query ran out of instructions or time and was stopped
+    Aborted {
+        message: String,
+        span: Option<Span>,
+    },
+    // Any other failure while executing the query
+    QueryError {
+        message: String,
+        span: Option<Span>,
+    },
+    // No running query has this id
+    QueryNotFound(u64),
+    // The HTTP request itself is malformed
+    BadRequest(String),
+    PayloadTooLarge(String),
+    // A setting has an invalid value
+    Config(String),
+    // Reading or writing the data directory failed
+    Io {
+        message: String,
+        source: io::Error,
+    },
+}
+
+impl Error {
+    pub fn type_error(message: String) -> Error {
+        Error::TypeError {
+            message,
+            span: None,
+        }
+    }
+
+    pub fn table_not_found(table_name: &str) -> Error {
+        Error::TableNotFound {
+            table_name: table_name.to_owned(),
+            span: None,
+        }
+    }
+
+    pub fn record_not_found(record_id: &RecordId) -> Error {
+        Error::RecordNotFound {
+            record_id: record_id.clone(),
+            span: None,
+        }
+    }
+
+    pub fn conflict(record_id: &RecordId, expected: u64, found: u64) -> Error {
+        Error::Conflict {
+            record_id: record_id.clone(),
+            expected,
+            found,
+            span: None,
+        }
+    }
+
+    pub fn query(message: String) -> Error {
+        Error::QueryError {
+            message,
+            span: None,
+        }
+    }
+
+    pub fn aborted(message: String) -> Error {
+        Error::Aborted {
+            message,
+            span: None,
+        }
+    }
+
+    pub fn io(message: &str, source: io::Error) -> Error {
+        Error::Io {
+            message: message.to_owned(),
+            source,
+        }
+    }
+
+    // Points a runtime error at the operation that raised it
+    pub fn at(mut self, at: Span) -> Error {
+        match &mut self {
+            Error::TypeError { span, .. }
+            | Error::StackUnderflow { span, .. }
+            | Error::TableNotFound { span, .. }
+            | Error::RecordNotFound { span, .. }
+            | Error::Conflict { span, .. }
+            | Error::Aborted { span, .. }
+            | Error::QueryError { span, .. } => {
+                span.get_or_insert(at);
+            }
+            _ => {}
+        }
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        match self {
+            Error::ParseError { .. }
+            | Error::TypeError { .. }
+            | Error::StackUnderflow { .. }
+            | Error::QueryError { .. }
+            | Error::BadRequest(_) => 400,
+            Error::TableNotFound { .. }
+            | Error::RecordNotFound { .. }
+            | Error::QueryNotFound(_) => 404,
+            Error::Aborted { .. } => 408,
+            Error::Conflict { .. } => 409,
+            Error::PayloadTooLarge(_) => 413,
+            Error::Config(_) | Error::Io { .. } => 500,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::ParseError { .. } => "parse_error",
+            Error::TypeError { .. } => "type_error",
+            Error::StackUnderflow { .. } => "stack_underflow",
+            Error::TableNotFound { .. } => "table_not_found",
+            Error::RecordNotFound { .. } => "record_not_found",
+            Error::Conflict { .. } => "conflict",
+            Error::QueryError { .. } => "query_error",
+            Error::Aborted { .. } => "query_aborted",
+            Error::QueryNotFound(_) => "query_not_found",
+            Error::BadRequest(_) => "bad_request",
+            Error::PayloadTooLarge(_) => "payload_too_large",
+            Error::Config(_) => "config_error",
+            Error::Io { .. } => "io_error",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Error::ParseError { message, .. }
+            | Error::TypeError { message, .. }
+            | Error::QueryError { message, .. }
+            | Error::Aborted { message, .. }
+            | Error::BadRequest(message)
+            | Error::PayloadTooLarge(message)
+            | Error::Config(message) => message.clone(),
+            Error::StackUnderflow { needed, found, .. } => format!(
+                "Stack must have atleast {} value(s), found {}",
+                needed, found
+            ),
+            Error::TableNotFound { table_name, .. } => {
+                format!("Table `{}` not found", table_name)
+            }
+            Error::RecordNotFound { record_id, .. } => format!(
+                "Record `@{}:{}` not found",
+                record_id.table_name, record_id.row
+            ),
+            Error::Conflict {
+                record_id,
+                expected,
+                found,
+                ..
+            } => format!(
+                "Record `@{}:{}` is at version {}, expected {}",
+                record_id.table_name, record_id.row, found, expected
+            ),
+            Error::QueryNotFound(id) => format!("No running query with id {}", id),
+            Error::Io { message, source } => format!("{}: {}", message, source),
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Error::ParseError { span, .. } => Some(*span),
+            Error::TypeError { span, .. }
+            | Error::StackUnderflow { span, .. }
+            | Error::TableNotFound { span, .. }
+            | Error::RecordNotFound { span, .. }
+            | Error::Conflict { span, .. }
+            | Error::Aborted { span, .. }
+            | Error::QueryError { span, .. } => *span,
+            _ => None,
+        }
+    }
+
+    // Line and column in the query, when known
+    pub fn position(&self) -> Option<(usize, usize)> {
+        self.span().map(|span| (span.line, span.col))
+    }
+
+    // The query line the error points at with a caret under
+    // the offending token, like
+    //
+    //   2 |   Foo
+    //     |   ^^^
+    pub fn snippet(&self, query: &str) -> Option<String> {
+        let span = self.span()?;
+        let line = query.lines().nth(span.line.checked_sub(1)?)?;
+
+        let number = span.line.to_string();
+        let gutter = " ".repeat(number.len());
+
+        // Tabs are kept so the caret lines up with the text above
+        let indent: String = line
+            .chars()
+            .take(span.col - 1)
+            .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+            .collect();
+        let carets = "^".repeat(span.len.max(1));
+
+        Some(format!(
+            "{} | {}\n{} | {}{}",
+            number, line, gutter, indent, carets
+        ))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.position() {
+            Some((line, col)) => write!(f, "{} at line {}:{}", self.message(), line, col),
+            None => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<HttpError> for Error {
+    fn from(err: HttpError) -> Error {
+        match err {
+            HttpError::PayloadTooLarge(_) => Error::PayloadTooLarge(err.message()),
+            _ => Error::BadRequest(err.message()),
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use std::io::{self, BufRead, Read, Write};
+
+// Longest request line, header or chunk size line accepted
+const MAX_LINE_LEN: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    // Header names are lowercased
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HttpError {
+    BadRequest(String),
+    PayloadTooLarge(usize),
+    // The connection was closed before a request was sent
+    Closed,
+}
+
+impl HttpError {
+    pub fn message(&self) -> String {
+        match self {
+            HttpError::BadRequest(message) => message.clone(),
+            HttpError::PayloadTooLarge(max) => {
+                format!("Request body is larger than {} bytes", max)
+            }
+            HttpError::Closed => "Connection closed".to_owned(),
+        }
+    }
+}
+
+fn bad_request(message: &str) -> HttpError {
+    HttpError::BadRequest(message.to_owned())
+}
+
+// Reads a CRLF (or LF) terminated line without the terminator
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>, HttpError> {
+    let mut line = Vec::new();
+    let read = reader
+        .by_ref()
+        .take(MAX_LINE_LEN as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .map_err(|err| HttpError::BadRequest(format!("{}", err)))?;
+
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        if line.len() > MAX_LINE_LEN {
+            return Err(bad_request("Line too long"));
+        }
+        return Err(bad_request("Unexpected end of request"));
+    }
+
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+
+    match String::from_utf8(line) {
+        Ok(line) => Ok(Some(line)),
+        Err(_) => Err(bad_request("Request head is not valid UTF-8")),
+    }
+}
+
+fn read_exact(reader: &mut impl BufRead, body: &mut Vec<u8>, len: usize) -> Result<(), HttpError> {
+    let start = body.len();
+    body.resize(start + len, 0);
+    reader
+        .read_exact(&mut body[start..])
+        .map_err(|_| bad_request("Unexpected end of body"))
+}
+
+fn read_chunked(reader: &mut impl BufRead, max_body_size: usize) -> Result<Vec<u8>, HttpError> {
+    let mut body = Vec::new();
+
+    loop {
+        let line = read_line(reader)?.ok_or_else(|| bad_request("Unexpected end of body"))?;
+
+        // Chunk extensions after `;` are ignored
+        let size = line.split(';').next().unwrap().trim();
+        let size = match usize::from_str_radix(size, 16) {
+            Ok(val) => val,
+            Err(_) => return Err(bad_request("Invalid chunk size")),
+        };
+
+        if size == 0 {
+            break;
+        }
+        // Checked so a huge chunk size can't overflow the sum
+        if size > max_body_size.saturating_sub(body.len()) {
+            return Err(HttpError::PayloadTooLarge(max_body_size));
+        }
+
+        read_exact(reader, &mut body, size)?;
+        if read_line(reader)? != Some(String::new()) {
+            return Err(bad_request("Chunk is not followed by CRLF"));
+        }
+    }
+
+    // Trailer fields are skipped
+    loop {
+        match read_line(reader)? {
+            Some(line) if !line.is_empty() => {}
+            _ => break,
+        }
+    }
+
+    Ok(body)
+}
+
+pub fn read_request(reader: &mut impl BufRead, max_body_size: usize) -> Result<Request, HttpError> {
+    let request_line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Err(HttpError::Closed),
+    };
+
+    let parts: Vec<_> = request_line.split(' ').collect();
+    if parts.len() != 3 || parts[0].is_empty() || parts[1].is_empty() {
+        return Err(bad_request("Malformed request line"));
+    }
+    if !parts[2].starts_with("HTTP/1.") {
+        return Err(HttpError::BadRequest(format!(
+            "Unsupported version `{}`",
+            parts[2]
+        )));
+    }
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?.ok_or_else(|| bad_request("Unexpected end of headers"))?;
+        if line.is_empty() {
+            break;
+        }
+
+        if headers.len() == MAX_HEADERS {
+            return Err(bad_request("Too many headers"));
+        }
+
+        let (name, value) = match line.split_once(':') {
+            Some(val) => val,
+            None => {
+                return Err(HttpError::BadRequest(format!(
+                    "Malformed header `{}`",
+                    line
+                )))
+            }
+        };
+        headers.push((name.trim().to_lowercase(), value.trim().to_owned()));
+    }
+
+    let mut request = Request {
+        method: parts[0].to_owned(),
+        path: parts[1].to_owned(),
+        headers,
+        body: Vec::new(),
+    };
+
+    let content_length = request.header("content-length");
+    let transfer_encoding = request.header("transfer-encoding");
+
+    request.body = match (content_length, transfer_encoding) {
+        (Some(_), Some(_)) => {
+            return Err(bad_request(
+                "Content-Length and Transfer-Encoding can't be used together",
+            ))
+        }
+        (None, Some(encoding)) => {
+            if !encoding.eq_ignore_ascii_case("chunked") {
+                return Err(HttpError::BadRequest(format!(
+                    "Unsupported transfer encoding `{}`",
+                    encoding
+                )));
+            }
+            read_chunked(reader, max_body_size)?
+        }
+        (Some(len), None) => {
+            let len = match len.parse::<usize>() {
+                Ok(val) => val,
+                Err(_) => return Err(bad_request("Invalid Content-Length")),
+            };
+            if len > max_body_size {
+                return Err(HttpError::PayloadTooLarge(max_body_size));
+            }
+
+            let mut body = Vec::new();
+            read_exact(reader, &mut body, len)?;
+            body
+        }
+        (None, None) => Vec::new(),
+    };
+
+    Ok(request)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        _ => "Unknown",
+    }
+}
+
+pub fn write_response(stream: &mut impl Write, status: u16, json: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n{}\r\n\r\n{}",
+        status,
+        reason(status),
+        json.len(),
+        "Content-Type: application/json",
+        json
+    );
+
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
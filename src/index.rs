@@ -0,0 +1,122 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    ops::Bound,
+};
+
+use crate::{intrinsics::Predicate, Value};
+
+// Index over a single field of a table, from field value to rows
+pub enum Index {
+    Hash(HashMap<Value, HashSet<u64>>),
+    // Kept sorted by value, serves range predicates and sorted scans
+    Ordered(BTreeMap<Value, BTreeSet<u64>>),
+}
+
+impl Index {
+    pub fn is_ordered(&self) -> bool {
+        matches!(self, Index::Ordered(_))
+    }
+
+    pub fn insert(&mut self, value: &Value, row: u64) {
+        match self {
+            Index::Hash(map) => {
+                map.entry(value.clone()).or_default().insert(row);
+            }
+            Index::Ordered(map) => {
+                map.entry(value.clone()).or_default().insert(row);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, value: &Value, row: u64) {
+        match self {
+            Index::Hash(map) => {
+                if let Some(rows) = map.get_mut(value) {
+                    rows.remove(&row);
+                    if rows.is_empty() {
+                        map.remove(value);
+                    }
+                }
+            }
+            Index::Ordered(map) => {
+                if let Some(rows) = map.get_mut(value) {
+                    rows.remove(&row);
+                    if rows.is_empty() {
+                        map.remove(value);
+                    }
+                }
+            }
+        }
+    }
+
+    // Rows whose field matches `predicate` against `value`, along with the
+    // indexed value. None when this kind of index can't answer it
+    pub fn lookup(&self, predicate: Predicate, value: &Value) -> Option<Vec<(&Value, u64)>> {
+        match self {
+            Index::Hash(map) => match predicate {
+                Predicate::Equal => Some(match map.get_key_value(value) {
+                    Some((value, rows)) => rows.iter().map(|row| (value, *row)).collect(),
+                    None => Vec::new(),
+                }),
+                _ => None,
+            },
+            Index::Ordered(map) => {
+                let bounds = match predicate {
+                    Predicate::Equal => (Bound::Included(value), Bound::Included(value)),
+                    Predicate::Less => (Bound::Unbounded, Bound::Excluded(value)),
+                    Predicate::LessEqual => (Bound::Unbounded, Bound::Included(value)),
+                    Predicate::Greater => (Bound::Excluded(value), Bound::Unbounded),
+                    Predicate::GreaterEqual => (Bound::Included(value), Bound::Unbounded),
+                    Predicate::Contains => return None,
+                };
+
+                Some(
+                    map.range::<Value, _>(bounds)
+                        .flat_map(|(value, rows)| rows.iter().map(move |row| (value, *row)))
+                        .collect(),
+                )
+            }
+        }
+    }
+
+    // Every indexed row and value sorted by value, None for unordered indexes
+    pub fn sorted(&self) -> Option<Vec<(&Value, u64)>> {
+        match self {
+            Index::Hash(_) => None,
+            Index::Ordered(map) => Some(
+                map.iter()
+                    .flat_map(|(value, rows)| rows.iter().map(move |row| (value, *row)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+// Has to agree with `Eq`, numbers that compare equal
+// hash the same whether they are ints or floats
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.rank().hash(state);
+        match self {
+            Value::Null => {}
+            Value::Bool(val) => val.hash(state),
+            Value::Int(val) => val.hash(state),
+            Value::Float(val) => {
+                if val.is_nan() {
+                    f64::NAN.to_bits().hash(state);
+                } else if val.fract() == 0.0 && *val >= i64::MIN as f64 && *val < i64::MAX as f64 {
+                    (*val as i64).hash(state);
+                } else {
+                    val.to_bits().hash(state);
+                }
+            }
+            Value::String(val) => val.hash(state),
+            Value::Id(record_id) | Value::RecordLink(record_id) => {
+                record_id.table_name.hash(state);
+                record_id.row.hash(state);
+            }
+            Value::Array(values) => values.hash(state),
+        }
+    }
+}
@@ -1,61 +1,298 @@
-use crate::{QueryResult, Record, RecordId, Records, Value};
-use std::collections::HashMap;
+use crate::index::Index;
+use crate::version::Versions;
+use crate::{Record, RecordId, ResultItem, Table, Value};
+use std::collections::{BTreeMap, HashMap};
 
-type FilterPredicate = fn(&Value, &Value) -> bool;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Predicate {
+    Equal,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    // Array field containing the value
+    Contains,
+}
+
+impl Predicate {
+    pub fn test(self, a: &Value, b: &Value) -> bool {
+        match self {
+            Predicate::Equal => a == b,
+            Predicate::Less => a < b,
+            Predicate::LessEqual => a <= b,
+            Predicate::Greater => a > b,
+            Predicate::GreaterEqual => a >= b,
+            Predicate::Contains => match a {
+                Value::Array(values) => values.contains(b),
+                _ => false,
+            },
+        }
+    }
+}
 
-pub fn match_predicate(predicate: Value) -> Option<FilterPredicate> {
+pub fn match_predicate(predicate: Value) -> Option<Predicate> {
     match predicate {
         Value::String(predicate) => match predicate.as_str() {
-            "==" => Some(|a, b| a == b),
-            "<" => Some(|a, b| a < b),
-            "<=" => Some(|a, b| a <= b),
-            ">" => Some(|a, b| a > b),
-            ">=" => Some(|a, b| a >= b),
+            "==" => Some(Predicate::Equal),
+            "<" => Some(Predicate::Less),
+            "<=" => Some(Predicate::LessEqual),
+            ">" => Some(Predicate::Greater),
+            ">=" => Some(Predicate::GreaterEqual),
+            "contains" => Some(Predicate::Contains),
             _ => None,
         },
         _ => None,
     }
 }
 
-pub fn set(records: &mut Records, record_id: &RecordId, key: String, value: Value) {
+// Writes go to the record's pending version, the values of older
+// versions stay in the indexes until those versions are collected
+pub fn set(table: &mut Table, record_id: &RecordId, key: String, value: Value) {
+    let key = key.to_lowercase();
+    let row = record_id.row;
+
+    table.version += 1;
+    let version = table.version;
+
+    let record = table.records.entry(row).or_default().pending();
+    let old = match record {
+        Some(record) => {
+            record.version = version;
+            record.fields.insert(key.clone(), value.clone())
+        }
+        None => {
+            let id = Value::Id(record_id.clone());
+            if let Some(index) = table.indexes.get_mut("id") {
+                index.insert(&id, row);
+            }
+
+            *record = Some(Record {
+                fields: HashMap::from([(String::from("id"), id), (key.clone(), value.clone())]),
+                version,
+            });
+            None
+        }
+    };
+
+    if let Some(index) = table.indexes.get_mut(&key) {
+        index.insert(&value, row);
+    }
+    if let Some(old) = old {
+        unindex(table, row, &key, &old);
+    }
+}
+
+pub fn delete(table: &mut Table, record_id: &RecordId) {
+    let row = record_id.row;
+    if let Some(versions) = table.records.get_mut(&row) {
+        if let Some(record) = versions.pending().take() {
+            unindex_record(table, row, &record);
+        }
+    }
+}
+
+pub fn unset(table: &mut Table, record_id: &RecordId, key: String) {
     let key = key.to_lowercase();
-    if let Some(record) = records.get_mut(&record_id.row) {
-        record.fields.insert(key, value);
+    let row = record_id.row;
+
+    let record = table
+        .records
+        .get_mut(&row)
+        .and_then(|versions| versions.pending().as_mut());
+    let old = record.and_then(|record| {
+        let old = record.fields.remove(&key)?;
+        table.version += 1;
+        record.version = table.version;
+        Some(old)
+    });
+    if let Some(old) = old {
+        unindex(table, row, &key, &old);
+    }
+}
+
+// Drops the version the running transaction wrote
+pub fn discard(table: &mut Table, row: u64) {
+    if let Some(versions) = table.records.get_mut(&row) {
+        let record = versions.discard();
+        if versions.is_empty() {
+            table.records.remove(&row);
+        }
+        if let Some(record) = record {
+            unindex_record(table, row, &record);
+        }
+    }
+}
+
+pub fn stamp(table: &mut Table, row: u64, ts: u64) {
+    if let Some(versions) = table.records.get_mut(&row) {
+        versions.stamp(ts);
+    }
+}
+
+// Drops the versions of the row no program reading at `oldest` or later can see
+pub fn collect(table: &mut Table, row: u64, oldest: u64) {
+    if let Some(versions) = table.records.get_mut(&row) {
+        let removed = versions.collect(oldest);
+        if versions.is_empty() {
+            table.records.remove(&row);
+        }
+        for record in removed {
+            unindex_record(table, row, &record);
+        }
+    }
+
+    match table
+        .records
+        .get(&row)
+        .is_some_and(Versions::is_collectable)
+    {
+        true => table.stale.insert(row),
+        false => table.stale.remove(&row),
+    };
+}
+
+// Collects the rows that kept versions for readers older than `oldest`
+pub fn collect_stale(table: &mut Table, oldest: u64) {
+    for row in std::mem::take(&mut table.stale) {
+        collect(table, row, oldest);
+    }
+}
+
+pub fn collect_all(table: &mut Table, oldest: u64) {
+    let rows: Vec<_> = table.records.keys().copied().collect();
+    for row in rows {
+        collect(table, row, oldest);
+    }
+}
+
+// Removes `value` from the index on `key` once no version of the row holds it
+fn unindex(table: &mut Table, row: u64, key: &str, value: &Value) {
+    let held = table
+        .records
+        .get(&row)
+        .is_some_and(|versions| versions.holds(key, value));
+    if let Some(index) = table.indexes.get_mut(key) {
+        if !held {
+            index.remove(value, row);
+        }
+    }
+}
+
+fn unindex_record(table: &mut Table, row: u64, record: &Record) {
+    for (key, value) in &record.fields {
+        unindex(table, row, key, value);
+    }
+}
+
+// Records keep their own id, so it has to follow the table name
+pub fn rename(table: &mut Table, table_name: &str) {
+    for versions in table.records.values_mut() {
+        for record in versions.records_mut() {
+            if let Some(Value::Id(record_id)) = record.fields.get_mut("id") {
+                record_id.table_name = table_name.to_owned();
+            }
+        }
+    }
+
+    if let Some(index) = table.indexes.get("id") {
+        let ordered = index.is_ordered();
+        create_index(table, "id".to_owned(), ordered);
+    }
+}
+
+// Builds an index over `key`, replacing any existing one
+pub fn create_index(table: &mut Table, key: String, ordered: bool) {
+    let key = key.to_lowercase();
+
+    let mut index = if ordered {
+        Index::Ordered(BTreeMap::new())
     } else {
-        records.insert(
-            record_id.row,
-            Record {
-                fields: HashMap::from([
-                    (String::from("id"), Value::Id(record_id.clone())),
-                    (key, value),
-                ]),
-            },
-        );
+        Index::Hash(HashMap::new())
+    };
+    for (row, versions) in &table.records {
+        for record in versions.records() {
+            if let Some(value) = record.fields.get(&key) {
+                index.insert(value, *row);
+            }
+        }
     }
+
+    table.indexes.insert(key, index);
 }
 
-// TODO: optimize this with indexes
 pub fn filter(
-    records: &mut Records,
-    result: &mut QueryResult,
+    table: &Table,
+    ts: u64,
+    result: &mut Vec<ResultItem>,
     key: String,
     value: Value,
-    predicate: FilterPredicate,
+    predicate: Predicate,
 ) {
     let key = key.to_lowercase();
-    for (_, record) in records.iter() {
-        let mut include = false;
-        for (field_key, field_value) in &record.fields {
-            if field_key.eq(&key) && predicate(field_value, &value) {
-                include = true;
-                break;
+
+    let push = |result: &mut Vec<ResultItem>, record: &Record| {
+        if let Value::Id(record_id) = record.fields.get("id").unwrap() {
+            result.push(ResultItem::Record(record_id.clone()));
+        };
+    };
+
+    let rows = table
+        .indexes
+        .get(&key)
+        .and_then(|index| index.lookup(predicate, &value));
+
+    if let Some(rows) = rows {
+        // The index holds the values of every version, a row
+        // matches under the value of the version visible at `ts`
+        for (indexed, row) in rows {
+            if let Some(record) = table.get(row, ts) {
+                if record.fields.get(&key) == Some(indexed) {
+                    push(result, record);
+                }
             }
         }
+        return;
+    }
+
+    for (_, record) in table.rows(ts) {
+        if let Some(field_value) = record.fields.get(&key) {
+            if predicate.test(field_value, &value) {
+                push(result, record);
+            }
+        }
+    }
+}
+
+// Records having the field `key`, in ascending order of its value
+pub fn order_by(table: &Table, ts: u64, result: &mut Vec<ResultItem>, key: String) {
+    let key = key.to_lowercase();
+
+    let rows: Vec<_> = match table.indexes.get(&key).and_then(|index| index.sorted()) {
+        Some(rows) => rows
+            .into_iter()
+            .filter(|(indexed, row)| {
+                table
+                    .get(*row, ts)
+                    .is_some_and(|record| record.fields.get(&key) == Some(indexed))
+            })
+            .map(|(_, row)| row)
+            .collect(),
+        None => {
+            let mut values: Vec<_> = table
+                .rows(ts)
+                .filter_map(|(row, record)| Some((record.fields.get(&key)?, row)))
+                .collect();
+            values.sort();
+            values.into_iter().map(|(_, row)| row).collect()
+        }
+    };
 
-        if include {
-            if let Value::Id(record_id) = record.fields.get("id").unwrap() {
-                result.push(record_id.clone());
-            };
+    for row in rows {
+        if let Some(Value::Id(record_id)) = table
+            .get(row, ts)
+            .and_then(|record| record.fields.get("id"))
+        {
+            result.push(ResultItem::Record(record_id.clone()));
         }
     }
 }
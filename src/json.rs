@@ -0,0 +1,118 @@
+use std::fmt::Write;
+
+// Streaming JSON writer, separators between values are
+// inserted automatically so callers only describe the structure
+#[derive(Default)]
+pub struct JsonWriter {
+    output: String,
+    // One entry per open object or array, true until
+    // the first value has been written into it
+    first: Vec<bool>,
+    // A key was written and its value is expected next
+    after_key: bool,
+}
+
+impl JsonWriter {
+    pub fn new() -> JsonWriter {
+        JsonWriter {
+            output: String::new(),
+            first: Vec::new(),
+            after_key: false,
+        }
+    }
+
+    fn separator(&mut self) {
+        if self.after_key {
+            self.after_key = false;
+            return;
+        }
+        if let Some(first) = self.first.last_mut() {
+            if !*first {
+                self.output.push(',');
+            }
+            *first = false;
+        }
+    }
+
+    pub fn begin_object(&mut self) {
+        self.separator();
+        self.output.push('{');
+        self.first.push(true);
+    }
+
+    pub fn end_object(&mut self) {
+        self.first.pop();
+        self.output.push('}');
+    }
+
+    pub fn begin_array(&mut self) {
+        self.separator();
+        self.output.push('[');
+        self.first.push(true);
+    }
+
+    pub fn end_array(&mut self) {
+        self.first.pop();
+        self.output.push(']');
+    }
+
+    pub fn key(&mut self, key: &str) {
+        self.separator();
+        escape(&mut self.output, key);
+        self.output.push(':');
+        self.after_key = true;
+    }
+
+    pub fn string(&mut self, value: &str) {
+        self.separator();
+        escape(&mut self.output, value);
+    }
+
+    pub fn int(&mut self, value: i64) {
+        self.separator();
+        write!(self.output, "{}", value).unwrap();
+    }
+
+    // JSON has no NaN or infinity, those are written as null
+    pub fn float(&mut self, value: f64) {
+        if !value.is_finite() {
+            self.null();
+            return;
+        }
+        self.separator();
+        write!(self.output, "{:?}", value).unwrap();
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.separator();
+        self.output.push_str(if value { "true" } else { "false" });
+    }
+
+    pub fn null(&mut self) {
+        self.separator();
+        self.output.push_str("null");
+    }
+
+    pub fn finish(self) -> String {
+        self.output
+    }
+}
+
+// Writes `str` as a quoted JSON string
+fn escape(output: &mut String, str: &str) {
+    output.push('"');
+    for ch in str.chars() {
+        match ch {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            '\u{08}' => output.push_str("\\b"),
+            '\u{0c}' => output.push_str("\\f"),
+            ch if (ch as u32) < 0x20 => write!(output, "\\u{:04x}", ch as u32).unwrap(),
+            ch => output.push(ch),
+        }
+    }
+    output.push('"');
+}
@@ -1,77 +1,1311 @@
 use query::*;
-use std::io::Write;
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
-use std::{collections::HashMap, io::BufReader};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{self, AtomicBool, AtomicU64};
+use std::sync::{
+    Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError, TryLockResult,
+};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{cmp::Ordering, collections::HashMap, fmt, io, io::BufReader};
 
+use crate::index::Index;
+use crate::json::JsonWriter;
+use crate::registry::{QueryInfo, Registry};
 use crate::server::ThreadPool;
+use crate::storage::{Mutation, Storage};
+use crate::version::{Versions, PENDING};
 
+#[macro_use]
+mod log;
+
+mod config;
+mod error;
+mod http;
+mod index;
 mod intrinsics;
+mod json;
 mod query;
+mod registry;
 mod server;
+mod signal;
+mod storage;
+mod version;
 
 #[cfg(test)]
 mod tests;
 
-#[derive(Debug)]
+pub use config::{ServerConfig, USAGE};
+pub use error::Error;
+pub use log::LogLevel;
+pub use query::Span;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Record {
     fields: HashMap<String, Value>,
+    // Raised by every write to the record, see `Table::version`
+    version: u64,
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Clone)]
+// Key the record version is returned under, it can't be used as a field
+const VERSION_KEY: &str = "_version";
+
+fn check_key(key: &str) -> Result<(), Error> {
+    if key.to_lowercase() == VERSION_KEY {
+        return Err(Error::query(format!("Field `{}` is reserved", VERSION_KEY)));
+    }
+    Ok(())
+}
+
+impl Record {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.fields.get(&key.to_lowercase())
+    }
+
+    // Every field of the record, including its `id`
+    pub fn fields(&self) -> &HashMap<String, Value> {
+        &self.fields
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct RecordId {
     table_name: String,
     row: u64,
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Clone)]
+impl RecordId {
+    // `table_name` is written without the leading `@`
+    pub fn new(table_name: &str, row: u64) -> RecordId {
+        RecordId {
+            table_name: table_name.to_owned(),
+            row,
+        }
+    }
+
+    pub fn table_name(&self) -> &str {
+        &self.table_name
+    }
+
+    pub fn row(&self) -> u64 {
+        self.row
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Value {
     // Id is composed of a table name and row id
     Id(RecordId),
+    Null,
+    Bool(bool),
     Int(i64),
     Float(f64),
     String(String),
-    // Array
-    // RecordLink(Id),
+    Array(Vec<Value>),
+    // Reference to another record, can be expanded in the output
+    RecordLink(RecordId),
+}
+
+impl Value {
+    // Values of different types are ordered by type
+    fn rank(&self) -> u8 {
+        match self {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Int(_) | Value::Float(_) => 2,
+            Value::String(_) => 3,
+            Value::Id(_) => 4,
+            Value::RecordLink(_) => 5,
+            Value::Array(_) => 6,
+        }
+    }
+}
+
+// Ints and floats compare by numeric value, NaN is
+// equal to itself and greater than every other number
+fn cmp_float(a: f64, b: f64) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => a.partial_cmp(&b).unwrap(),
+    }
+}
+
+fn cmp_int_float(a: i64, b: f64) -> Ordering {
+    // 2^63, the first float above every i64
+    const LIMIT: f64 = 9_223_372_036_854_775_808.0;
+
+    if b.is_nan() || b >= LIMIT {
+        return Ordering::Less;
+    }
+    if b < -LIMIT {
+        return Ordering::Greater;
+    }
+
+    // Compare whole parts exactly, then the fraction decides
+    let whole = b.trunc();
+    a.cmp(&(whole as i64)).then(cmp_float(whole, b))
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (Value::Null, Value::Null) => Ordering::Equal,
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (Value::Int(a), Value::Int(b)) => a.cmp(b),
+            (Value::Float(a), Value::Float(b)) => cmp_float(*a, *b),
+            (Value::Int(a), Value::Float(b)) => cmp_int_float(*a, *b),
+            (Value::Float(a), Value::Int(b)) => cmp_int_float(*b, *a).reverse(),
+            (Value::String(a), Value::String(b)) => a.cmp(b),
+            (Value::Id(a), Value::Id(b)) => a.cmp(b),
+            (Value::RecordLink(a), Value::RecordLink(b)) => a.cmp(b),
+            (Value::Array(a), Value::Array(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Value) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+impl From<i64> for Value {
+    fn from(val: i64) -> Value {
+        Value::Int(val)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(val: f64) -> Value {
+        Value::Float(val)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(val: bool) -> Value {
+        Value::Bool(val)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(val: &str) -> Value {
+        Value::String(val.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(val: String) -> Value {
+        Value::String(val)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(val: Vec<Value>) -> Value {
+        Value::Array(val)
+    }
+}
+
+type Records = HashMap<u64, Versions>;
+
+#[derive(Default)]
+pub struct Table {
+    records: Records,
+    // Hashmap from field name to its index
+    indexes: HashMap<String, Index>,
+    // Newest version given to a record. Versions are never reused, so
+    // a record deleted and set again can't pass for the old one
+    version: u64,
+    // Rows keeping versions for running readers, collected once they finish
+    stale: BTreeSet<u64>,
+}
+
+impl Table {
+    // The record as a program reading at `ts` sees it
+    fn get(&self, row: u64, ts: u64) -> Option<&Record> {
+        self.records.get(&row)?.get(ts)
+    }
+
+    fn rows(&self, ts: u64) -> impl Iterator<Item = (u64, &Record)> {
+        self.records
+            .iter()
+            .filter_map(move |(row, versions)| Some((*row, versions.get(ts)?)))
+    }
+
+    fn len(&self, ts: u64) -> usize {
+        self.rows(ts).count()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ResultItem {
+    Record(RecordId),
+    // Table name and number of records
+    Table { name: String, rows: usize },
+}
+#[derive(Debug, Default)]
+struct QueryResult<'a> {
+    items: Vec<ResultItem>,
+    // How many levels of record links are expanded in the output
+    depth: usize,
+    // Read-only programs keep their snapshot until the output is built
+    reader: Option<Reader<'a>>,
+}
+
+impl QueryResult<'_> {
+    // Copy of the record as the output shows it. Readers render it as
+    // they saw it, even from a table dropped or renamed since, the
+    // output of writers shows the latest commit
+    fn get(&self, database: &Database, record_id: &RecordId) -> Option<Record> {
+        match &self.reader {
+            Some(reader) => {
+                let table = reader.tables.get(&record_id.table_name)?.read();
+                table.get(record_id.row, reader.ts).cloned()
+            }
+            None => database.get(record_id),
+        }
+    }
+}
+
+// Result of `Database::query`, records are copied out as a read-only
+// query saw them, or as they were when a writing query finished
+#[derive(Debug, Default, PartialEq)]
+pub struct QueryOutput {
+    pub items: Vec<OutputItem>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum OutputItem {
+    Record { id: RecordId, record: Record },
+    // Table name and number of records
+    Table { name: String, rows: usize },
+}
+
+// How much work one query may do before it is aborted, 0 disables a limit
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    // Operations executed, each pass through a range counts again
+    pub max_instructions: u64,
+    pub timeout: Duration,
+    // Highest `Expand` depth, rendering recurses once per level
+    pub max_expand_depth: u64,
+}
+
+impl Limits {
+    // The stricter of the two for each limit
+    pub fn min(self, other: Limits) -> Limits {
+        fn stricter<T: Ord + Default>(a: T, b: T) -> T {
+            match (a == T::default(), b == T::default()) {
+                (true, _) => b,
+                (_, true) => a,
+                _ => a.min(b),
+            }
+        }
+
+        Limits {
+            max_instructions: stricter(self.max_instructions, other.max_instructions),
+            timeout: stricter(self.timeout, other.timeout),
+            max_expand_depth: stricter(self.max_expand_depth, other.max_expand_depth),
+        }
+    }
+}
+
+// Tracks a running program against its limits
+struct Budget {
+    limits: Limits,
+    deadline: Option<Instant>,
+    executed: u64,
+    // Set to stop the program, see `Registry::cancel`
+    cancelled: Option<Arc<AtomicBool>>,
+}
+
+// The clock is only read every so many operations
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+// How long a program waiting for a lock sleeps between attempts
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+impl Budget {
+    // The timeout counts from here, including the wait for locks
+    fn new(limits: Limits) -> Budget {
+        Budget {
+            limits,
+            deadline: match limits.timeout.is_zero() {
+                true => None,
+                false => Some(Instant::now() + limits.timeout),
+            },
+            executed: 0,
+            cancelled: None,
+        }
+    }
+
+    // Called before every operation
+    fn spend(&mut self) -> Result<(), Error> {
+        self.executed += 1;
+        self.check_cancelled()?;
+
+        let max = self.limits.max_instructions;
+        if max > 0 && self.executed > max {
+            return Err(Error::aborted(format!(
+                "Query exceeded its budget of {} instructions",
+                max
+            )));
+        }
+
+        if self.executed % DEADLINE_CHECK_INTERVAL == 1 {
+            self.check_deadline()?;
+        }
+        Ok(())
+    }
+
+    // Takes a lock with `try_lock`, retrying until the program
+    // is cancelled or runs out of time
+    fn wait<G>(&self, try_lock: impl Fn() -> TryLockResult<G>) -> Result<G, Error> {
+        loop {
+            match try_lock() {
+                Ok(guard) => return Ok(guard),
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Poisoned(err)) => panic!("{}", err),
+            }
+            self.check_cancelled()?;
+            self.check_deadline()?;
+            thread::sleep(LOCK_RETRY_INTERVAL);
+        }
+    }
+
+    fn check_cancelled(&self) -> Result<(), Error> {
+        match &self.cancelled {
+            Some(cancelled) if cancelled.load(atomic::Ordering::Relaxed) => {
+                Err(Error::aborted("Query was cancelled".to_owned()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_deadline(&self) -> Result<(), Error> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(Error::aborted(format!(
+                "Query timed out after {:?}",
+                self.limits.timeout
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    // Directory holding the log and snapshots, the
+    // database only lives in memory when it is None
+    pub data_dir: Option<PathBuf>,
+}
+
+// Writers hold `writer` for their whole program, so the pending
+// versions of a table all belong to one transaction. `table` is
+// only locked for one operation at a time, which lets readers
+// run while a writer is still in the middle of its program
+struct TableEntry {
+    writer: Mutex<()>,
+    table: RwLock<Table>,
+    // Name the catalog holds the table under, None once it is dropped.
+    // Only changed with `writer` held, so a writer can tell whether the
+    // table was dropped or renamed while it waited for the lock
+    name: Mutex<Option<String>>,
+}
+
+impl TableEntry {
+    fn new(name: &str, table: Table) -> Arc<TableEntry> {
+        Arc::new(TableEntry {
+            writer: Mutex::default(),
+            table: RwLock::new(table),
+            name: Mutex::new(Some(name.to_owned())),
+        })
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Table> {
+        self.table.read().unwrap()
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Table> {
+        self.table.write().unwrap()
+    }
+
+    fn is_named(&self, name: &str) -> bool {
+        self.name.lock().unwrap().as_deref() == Some(name)
+    }
+
+    fn rename(&self, name: Option<&str>) {
+        *self.name.lock().unwrap() = name.map(str::to_owned);
+    }
+}
+
+// Table name to table, each table has its own lock. Programs
+// keep the tables they use after the catalog is unlocked
+type Catalog = HashMap<String, Arc<TableEntry>>;
+
+pub struct Database {
+    // Only locked while programs look up their tables, and for the
+    // whole program when it drops or renames tables, builds an index
+    // or takes a snapshot
+    tables: RwLock<Catalog>,
+    // Write-ahead log and snapshots, every mutation is appended
+    // here before the query result is sent back
+    storage: Mutex<Option<Storage>>,
+    // Timestamp of the latest commit
+    clock: AtomicU64,
+    // Timestamps running readers started at and how many started
+    // at each, the versions they see are kept until they finish
+    readers: Mutex<BTreeMap<u64, usize>>,
+}
+pub type DatabaseRef = Arc<Database>;
+
+const DEFAULT_TABLE: &str = "0";
+// How long the accept loop sleeps when no connection is waiting
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+
+impl Database {
+    // Opens the database stored in the configured directory, loading
+    // the latest snapshot and replaying the log written after it
+    pub fn open(config: Config) -> Result<Database, Error> {
+        let dir = match &config.data_dir {
+            Some(dir) => dir,
+            None => {
+                let entry = TableEntry::new(DEFAULT_TABLE, Table::default());
+                let catalog = Catalog::from([(DEFAULT_TABLE.to_owned(), entry)]);
+                return Ok(Database::new(catalog, None));
+            }
+        };
+
+        let (storage, tables, mutations) = match Storage::open(dir) {
+            Ok(val) => val,
+            Err(err) => return Err(Error::io("Unable to open storage", err)),
+        };
+
+        let mut catalog: Catalog = tables
+            .into_iter()
+            .map(|(name, table)| {
+                let entry = TableEntry::new(&name, table);
+                (name, entry)
+            })
+            .collect();
+        create_table(&mut catalog, DEFAULT_TABLE);
+
+        for mutation in &mutations {
+            apply(&mut catalog, mutation);
+        }
+
+        // Replayed writes were committed before anything can read
+        for entry in catalog.values() {
+            let mut table = entry.write();
+            for versions in table.records.values_mut() {
+                versions.stamp(0);
+            }
+            intrinsics::collect_all(&mut table, 0);
+        }
+
+        info!("Replayed {} log entries", mutations.len());
+
+        Ok(Database::new(catalog, Some(storage)))
+    }
+
+    fn new(catalog: Catalog, storage: Option<Storage>) -> Database {
+        Database {
+            tables: RwLock::new(catalog),
+            storage: Mutex::new(storage),
+            clock: AtomicU64::new(0),
+            readers: Mutex::default(),
+        }
+    }
+
+    // Parses and runs `query`, mutations are durable once it returns
+    pub fn query(&self, query: &str) -> Result<QueryOutput, Error> {
+        self.query_with_limits(query, Limits::default())
+    }
+
+    // Like `query`, aborting and rolling back once a limit is hit
+    pub fn query_with_limits(&self, query: &str, limits: Limits) -> Result<QueryOutput, Error> {
+        let program = query::parse(query)?;
+        let mut result = execute_program(self, program, Budget::new(limits))?;
+
+        let mut output = QueryOutput::default();
+        for item in std::mem::take(&mut result.items) {
+            match item {
+                ResultItem::Record(id) => {
+                    // The record may have been deleted later in the program
+                    if let Some(record) = result.get(self, &id) {
+                        output.items.push(OutputItem::Record { id, record });
+                    }
+                }
+                ResultItem::Table { name, rows } => {
+                    output.items.push(OutputItem::Table { name, rows })
+                }
+            }
+        }
+        Ok(output)
+    }
+
+    // Copy of the record as of the latest commit, the
+    // table is only locked while copying
+    pub fn get(&self, record_id: &RecordId) -> Option<Record> {
+        let catalog = self.tables.read().unwrap();
+        let table = catalog.get(&record_id.table_name)?.read();
+        let ts = self.clock.load(atomic::Ordering::SeqCst);
+        table.get(record_id.row, ts).cloned()
+    }
+
+    // Creates the record and its table when missing
+    pub fn set(
+        &self,
+        record_id: &RecordId,
+        key: &str,
+        value: impl Into<Value>,
+    ) -> Result<(), Error> {
+        check_key(key)?;
+        let mutation = Mutation::Set {
+            record_id: record_id.clone(),
+            key: key.to_owned(),
+            value: value.into(),
+        };
+
+        let names = BTreeSet::from([record_id.table_name.clone()]);
+        let mut budget = Budget::new(Limits::default());
+        self.write_tables(&names, &mut budget, |access, _| {
+            Transaction::single(self, access, mutation)
+        })
+    }
+
+    pub fn delete(&self, record_id: &RecordId) -> Result<(), Error> {
+        if !self
+            .tables
+            .read()
+            .unwrap()
+            .contains_key(&record_id.table_name)
+        {
+            return Err(Error::table_not_found(&record_id.table_name));
+        }
+
+        let names = BTreeSet::from([record_id.table_name.clone()]);
+        let mut budget = Budget::new(Limits::default());
+        self.write_tables(&names, &mut budget, |access, _| {
+            let exists = access
+                .table(&record_id.table_name)
+                .is_some_and(|table| table.get(record_id.row, PENDING).is_some());
+            if !exists {
+                return Err(Error::record_not_found(record_id));
+            }
+
+            let mutation = Mutation::Delete {
+                record_id: record_id.clone(),
+            };
+            Transaction::single(self, access, mutation)
+        })
+    }
+
+    // Runs `f` with the named tables locked for writing. Missing tables
+    // are added under a short exclusive lock of the catalog first, and
+    // removed again when nothing was written to them
+    fn write_tables<R>(
+        &self,
+        names: &BTreeSet<String>,
+        budget: &mut Budget,
+        f: impl FnOnce(&mut Access, &mut Budget) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        let mut created = Vec::new();
+        let result = loop {
+            let entries: Vec<_> = match budget.wait(|| self.tables.try_read()) {
+                Ok(catalog) => names
+                    .iter()
+                    .filter_map(|name| Some((name.as_str(), Arc::clone(catalog.get(name)?))))
+                    .collect(),
+                Err(err) => break Err(err),
+            };
+
+            if entries.len() < names.len() {
+                let mut catalog = match budget.wait(|| self.tables.try_write()) {
+                    Ok(val) => val,
+                    Err(err) => break Err(err),
+                };
+                for name in names {
+                    if !catalog.contains_key(name) {
+                        created.push(Arc::clone(create_table(&mut catalog, name)));
+                    }
+                }
+                continue;
+            }
+
+            let tables = match entries
+                .iter()
+                .map(|(name, entry)| Ok((*name, TableGuard::write(entry, budget)?)))
+                .collect()
+            {
+                Ok(val) => val,
+                Err(err) => break Err(err),
+            };
+            // Tables dropped or renamed while this waited are looked up again
+            if !entries.iter().all(|(name, entry)| entry.is_named(name)) {
+                continue;
+            }
+
+            let mut access = Access::Tables {
+                tables,
+                ts: PENDING,
+            };
+            break f(&mut access, budget);
+        };
+
+        if !created.is_empty() {
+            let mut catalog = self.tables.write().unwrap();
+            for entry in created {
+                let _writer = entry.writer.lock().unwrap();
+                let table = entry.read();
+                if table.version > 0 || !table.indexes.is_empty() {
+                    continue;
+                }
+
+                let name = entry.name.lock().unwrap().clone();
+                if let Some(name) = name {
+                    catalog.remove(&name);
+                    entry.rename(None);
+                }
+            }
+        }
+        result
+    }
+
+    // Runs `f` with the catalog and every table locked. Writers don't
+    // hold the catalog lock while they run, only their tables' locks
+    fn exclusive<R>(
+        &self,
+        budget: &mut Budget,
+        f: impl FnOnce(&mut Catalog, &mut Budget) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        let mut catalog = budget.wait(|| self.tables.try_write())?;
+        let entries: BTreeMap<_, _> = catalog
+            .iter()
+            .map(|(name, entry)| (name.clone(), Arc::clone(entry)))
+            .collect();
+        let _writers = entries
+            .values()
+            .map(|entry| budget.wait(|| entry.writer.try_lock()))
+            .collect::<Result<Vec<_>, _>>()?;
+        f(&mut catalog, budget)
+    }
+
+    // Makes the mutations durable and visible to the readers starting
+    // after it returns. Commits are serialized, `publish` gets the commit
+    // timestamp to stamp the pending versions with and the oldest
+    // timestamp a reader still sees
+    fn commit(&self, mutations: &[Mutation], publish: impl FnOnce(u64, u64)) -> Result<(), Error> {
+        if mutations.is_empty() {
+            return Ok(());
+        }
+
+        let mut storage = self.storage.lock().unwrap();
+        if let Some(storage) = storage.as_mut() {
+            if let Err(err) = storage.append(mutations) {
+                return Err(Error::io("Unable to write log", err));
+            }
+        }
+
+        // Readers register under the same lock, one starting
+        // now either counts for `oldest` or sees this commit
+        let readers = self.readers.lock().unwrap();
+        let ts = self.clock.load(atomic::Ordering::SeqCst) + 1;
+        let oldest = readers.keys().next().copied().unwrap_or(ts);
+        publish(ts, oldest);
+        self.clock.store(ts, atomic::Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Readers see the commits made before they start, in the tables
+    // of `catalog` which has to stay locked until this returns
+    fn begin_read(&self, catalog: &Catalog) -> Reader<'_> {
+        let mut readers = self.readers.lock().unwrap();
+        let ts = self.clock.load(atomic::Ordering::SeqCst);
+        *readers.entry(ts).or_default() += 1;
+        Reader {
+            database: self,
+            ts,
+            tables: catalog.clone(),
+        }
+    }
+
+    // Writes the tables to a snapshot so older log segments can go
+    fn snapshot(&self) -> Result<(), Error> {
+        let mut budget = Budget::new(Limits::default());
+        self.exclusive(&mut budget, |catalog, _| self.write_snapshot(catalog))
+    }
+
+    // Every table has to be locked for writing
+    fn write_snapshot(&self, catalog: &Catalog) -> Result<(), Error> {
+        // No writer runs while the tables are locked, but readers may
+        // still be running or rendering their output at their snapshot
+        let oldest = {
+            let readers = self.readers.lock().unwrap();
+            let ts = self.clock.load(atomic::Ordering::SeqCst);
+            readers.keys().next().copied().unwrap_or(ts)
+        };
+        let mut tables: Vec<_> = catalog
+            .iter()
+            .map(|(name, entry)| (name.as_str(), entry.write()))
+            .collect();
+        for (_, table) in &mut tables {
+            intrinsics::collect_all(table, oldest);
+        }
+
+        if let Some(storage) = self.storage.lock().unwrap().as_mut() {
+            let tables: Vec<_> = tables
+                .iter()
+                .map(|(name, table)| (*name, &**table))
+                .collect();
+            if let Err(err) = storage.snapshot(&tables) {
+                return Err(Error::io("Unable to write snapshot", err));
+            }
+        }
+        Ok(())
+    }
+}
+
+// A running read-only program, deregistered when dropped
+struct Reader<'a> {
+    database: &'a Database,
+    ts: u64,
+    // Every table as of `ts`, kept for rendering the output
+    tables: Catalog,
+}
+
+impl fmt::Debug for Reader<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reader").field("ts", &self.ts).finish()
+    }
 }
 
-type Records = HashMap<u64, Record>;
-type QueryResult = Vec<RecordId>;
+impl Drop for Reader<'_> {
+    fn drop(&mut self) {
+        let oldest = {
+            let mut readers = self.database.readers.lock().unwrap();
+            if let Some(count) = readers.get_mut(&self.ts) {
+                *count -= 1;
+                if *count == 0 {
+                    readers.remove(&self.ts);
+                }
+            }
+
+            // Only the oldest readers hold back older versions
+            match readers.keys().next() {
+                Some(ts) if *ts <= self.ts => return,
+                Some(ts) => *ts,
+                None => self.database.clock.load(atomic::Ordering::SeqCst),
+            }
+        };
+
+        // Versions kept for this reader go now rather than on the next
+        // write to their record, the readers lock is released first
+        // since commits take it before the table locks
+        let tables: Vec<_> = self
+            .database
+            .tables
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        for entry in tables {
+            if !entry.read().stale.is_empty() {
+                intrinsics::collect_stale(&mut entry.write(), oldest);
+            }
+        }
+    }
+}
+
+fn create_table<'a>(catalog: &'a mut Catalog, table_name: &str) -> &'a Arc<TableEntry> {
+    catalog
+        .entry(table_name.to_owned())
+        .or_insert_with(|| TableEntry::new(table_name, Table::default()))
+}
+
+fn apply(catalog: &mut Catalog, mutation: &Mutation) {
+    match mutation {
+        Mutation::Set { record_id, .. } => {
+            let entry = create_table(catalog, &record_id.table_name);
+            apply_to_table(&mut entry.write(), mutation);
+        }
+        Mutation::Delete { record_id } | Mutation::Unset { record_id, .. } => {
+            if let Some(entry) = catalog.get(&record_id.table_name) {
+                apply_to_table(&mut entry.write(), mutation);
+            }
+        }
+        Mutation::DropTable { table_name } => {
+            if let Some(entry) = catalog.remove(table_name) {
+                entry.rename(None);
+            }
+        }
+        Mutation::RenameTable { from, to } => {
+            if let Some(entry) = catalog.remove(from) {
+                intrinsics::rename(&mut entry.write(), to);
+                entry.rename(Some(to));
+                catalog.insert(to.clone(), entry);
+            }
+        }
+        Mutation::CreateIndex {
+            table_name,
+            key,
+            ordered,
+        } => {
+            let entry = create_table(catalog, table_name);
+            intrinsics::create_index(&mut entry.write(), key.clone(), *ordered);
+        }
+    }
+}
+
+// Applies mutations that only change records
+fn apply_to_table(table: &mut Table, mutation: &Mutation) {
+    match mutation {
+        Mutation::Set {
+            record_id,
+            key,
+            value,
+        } => intrinsics::set(table, record_id, key.clone(), value.clone()),
+        Mutation::Delete { record_id } => intrinsics::delete(table, record_id),
+        Mutation::Unset { record_id, key } => intrinsics::unset(table, record_id, key.clone()),
+        _ => unreachable!("{:?} changes the catalog", mutation),
+    }
+}
+
+fn assert_stack_len(stack: &[Value], n: usize) -> Result<(), Error> {
+    if stack.len() < n {
+        return Err(Error::StackUnderflow {
+            needed: n,
+            found: stack.len(),
+            span: None,
+        });
+    }
+    Ok(())
+}
+
+enum TableGuard<'a> {
+    // Readers lock the table for one operation at a time
+    Read(&'a TableEntry),
+    // Writers also keep other writers out until they finish
+    Write {
+        entry: &'a TableEntry,
+        _writer: MutexGuard<'a, ()>,
+    },
+}
+
+impl<'a> TableGuard<'a> {
+    // Gives up once the program is cancelled or out of time
+    fn write(entry: &'a TableEntry, budget: &Budget) -> Result<TableGuard<'a>, Error> {
+        Ok(TableGuard::Write {
+            entry,
+            _writer: budget.wait(|| entry.writer.try_lock())?,
+        })
+    }
+
+    fn entry(&self) -> &'a TableEntry {
+        match self {
+            TableGuard::Read(entry) | TableGuard::Write { entry, .. } => entry,
+        }
+    }
+}
+
+// Tables a running program can reach
+enum Access<'a> {
+    // Structural programs hold the whole catalog
+    Catalog(&'a mut Catalog),
+    // Other programs name the tables they reference up front,
+    // readers see them as of `ts` and writers at PENDING
+    Tables {
+        tables: BTreeMap<&'a str, TableGuard<'a>>,
+        ts: u64,
+    },
+}
+
+impl Access<'_> {
+    // Timestamp the program reads at
+    fn ts(&self) -> u64 {
+        match self {
+            Access::Catalog(_) => PENDING,
+            Access::Tables { ts, .. } => *ts,
+        }
+    }
+
+    fn table(&mut self, table_name: &str) -> Option<RwLockReadGuard<'_, Table>> {
+        match self {
+            Access::Catalog(catalog) => Some(catalog.get(table_name)?.read()),
+            Access::Tables { tables, .. } => Some(tables.get(table_name)?.entry().read()),
+        }
+    }
+
+    // Names and sizes of every reachable table, sorted by name
+    fn tables(&mut self) -> Vec<(String, usize)> {
+        let mut tables: Vec<_> = match self {
+            Access::Catalog(catalog) => catalog
+                .iter()
+                .map(|(name, entry)| (name.clone(), entry.read().len(PENDING)))
+                .collect(),
+            Access::Tables { tables, ts } => tables
+                .iter()
+                .map(|(name, table)| (name.to_string(), table.entry().read().len(*ts)))
+                .collect(),
+        };
+        tables.sort();
+        tables
+    }
+
+    // Only tables locked for writing can be changed
+    fn table_mut(&mut self, table_name: &str) -> Option<RwLockWriteGuard<'_, Table>> {
+        match self {
+            Access::Catalog(catalog) => Some(catalog.get(table_name)?.write()),
+            Access::Tables { tables, .. } => match tables.get(table_name)? {
+                TableGuard::Read(_) => None,
+                TableGuard::Write { entry, .. } => Some(entry.write()),
+            },
+        }
+    }
+
+    // Applies the mutation and returns how to revert it
+    fn apply(&mut self, mutation: &Mutation) -> Result<Undo, Error> {
+        if let Access::Catalog(catalog) = self {
+            let undo = match mutation {
+                Mutation::Set { record_id, .. } if !catalog.contains_key(&record_id.table_name) => {
+                    Undo::RemoveTable(record_id.table_name.clone())
+                }
+                Mutation::Set { record_id, .. }
+                | Mutation::Delete { record_id }
+                | Mutation::Unset { record_id, .. } => Undo::Record {
+                    record_id: record_id.clone(),
+                    version: catalog[&record_id.table_name].read().version,
+                },
+                Mutation::DropTable { table_name } => {
+                    // The table is kept whole so its indexes come back too
+                    return match catalog.remove(table_name) {
+                        Some(table) => {
+                            table.rename(None);
+                            Ok(Undo::RestoreTable {
+                                table_name: table_name.clone(),
+                                table,
+                            })
+                        }
+                        None => Err(Error::table_not_found(table_name)),
+                    };
+                }
+                Mutation::RenameTable { from, to } => Undo::Apply(Mutation::RenameTable {
+                    from: to.clone(),
+                    to: from.clone(),
+                }),
+                Mutation::CreateIndex {
+                    table_name, key, ..
+                } => match catalog.contains_key(table_name) {
+                    true => Undo::RemoveIndex {
+                        table_name: table_name.clone(),
+                        key: key.clone(),
+                    },
+                    false => Undo::RemoveTable(table_name.clone()),
+                },
+            };
+            apply(catalog, mutation);
+            return Ok(undo);
+        }
+
+        let record_id = match mutation {
+            Mutation::Set { record_id, .. }
+            | Mutation::Delete { record_id }
+            | Mutation::Unset { record_id, .. } => record_id,
+            _ => return Err(Error::query(format!("{:?} needs every table", mutation))),
+        };
+        match self.table_mut(&record_id.table_name) {
+            Some(mut table) => {
+                let version = table.version;
+                apply_to_table(&mut table, mutation);
+                Ok(Undo::Record {
+                    record_id: record_id.clone(),
+                    version,
+                })
+            }
+            None => Err(Error::query(format!(
+                "Table `{}` is not locked for writing",
+                record_id.table_name
+            ))),
+        }
+    }
+
+    fn undo(&mut self, undo: Undo) {
+        match undo {
+            Undo::Record { record_id, version } => {
+                if let Some(mut table) = self.table_mut(&record_id.table_name) {
+                    intrinsics::discard(&mut table, record_id.row);
+                    table.version = version;
+                }
+            }
+            Undo::RemoveIndex { table_name, key } => {
+                if let Some(mut table) = self.table_mut(&table_name) {
+                    table.indexes.remove(&key);
+                }
+            }
+            undo => {
+                let catalog = match self {
+                    Access::Catalog(catalog) => catalog,
+                    Access::Tables { .. } => {
+                        unreachable!("only records change without the catalog")
+                    }
+                };
+                match undo {
+                    Undo::RemoveTable(table_name) => {
+                        if let Some(table) = catalog.remove(&table_name) {
+                            table.rename(None);
+                        }
+                    }
+                    Undo::RestoreTable { table_name, table } => {
+                        table.rename(Some(&table_name));
+                        catalog.insert(table_name, table);
+                    }
+                    Undo::Apply(mutation) => apply(catalog, &mutation),
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+
+    // Commits the pending version of the record and drops
+    // the versions no reader needs anymore
+    fn publish(&mut self, record_id: &RecordId, ts: u64, oldest: u64) {
+        if let Some(mut table) = self.table_mut(&record_id.table_name) {
+            intrinsics::stamp(&mut table, record_id.row, ts);
+            intrinsics::collect(&mut table, record_id.row, oldest);
+        }
+    }
+}
+
+// Reverts one write of a transaction that is rolled back
+enum Undo {
+    // Drops the pending version of the record and gives
+    // the table back the version it had before the write
+    Record {
+        record_id: RecordId,
+        version: u64,
+    },
+    // Removes a table the transaction created
+    RemoveTable(String),
+    // Puts back a dropped table along with its indexes
+    RestoreTable {
+        table_name: String,
+        table: Arc<TableEntry>,
+    },
+    RemoveIndex {
+        table_name: String,
+        key: String,
+    },
+    // Writes that are undone by another write, like renames
+    Apply(Mutation),
+}
+
+// Writes made since the program started or since the last `Begin`,
+// `Commit` or `Rollback`. They reach the log together when the
+// transaction commits and are undone together otherwise
+#[derive(Default)]
+struct Transaction {
+    // Index of the `Begin` that opened it, None for the
+    // implicit transaction every program runs in
+    begin: Option<usize>,
+    log: Vec<Mutation>,
+    // Newest last, so it is undone from the back
+    undo: Vec<Undo>,
+    // Records with a pending version, under their current table name
+    touched: BTreeSet<RecordId>,
+    // Set by `Snapshot`, the snapshot is taken after the commit
+    // so it never holds writes that are rolled back
+    snapshot: bool,
+}
+
+impl Transaction {
+    // Runs a single mutation as its own transaction
+    fn single(database: &Database, access: &mut Access, mutation: Mutation) -> Result<(), Error> {
+        let mut transaction = Transaction::default();
+        transaction.write(access, mutation)?;
+        transaction.commit(database, access)
+    }
+
+    fn write(&mut self, access: &mut Access, mutation: Mutation) -> Result<(), Error> {
+        let undo = access.apply(&mutation)?;
+        match &mutation {
+            Mutation::Set { record_id, .. }
+            | Mutation::Delete { record_id }
+            | Mutation::Unset { record_id, .. } => {
+                self.touched.insert(record_id.clone());
+            }
+            Mutation::DropTable { table_name } => {
+                self.touched
+                    .retain(|record_id| record_id.table_name != *table_name);
+            }
+            Mutation::RenameTable { from, to } => {
+                self.touched = std::mem::take(&mut self.touched)
+                    .into_iter()
+                    .map(|record_id| match record_id.table_name == *from {
+                        true => RecordId::new(to, record_id.row),
+                        false => record_id,
+                    })
+                    .collect();
+            }
+            Mutation::CreateIndex { .. } => {}
+        }
+        self.undo.push(undo);
+        self.log.push(mutation);
+        Ok(())
+    }
+
+    // Has to run while the tables are still locked to keep the log in order
+    fn commit(&mut self, database: &Database, access: &mut Access) -> Result<(), Error> {
+        let touched = &self.touched;
+        let result = database.commit(&self.log, |ts, oldest| {
+            for record_id in touched {
+                access.publish(record_id, ts, oldest);
+            }
+        });
+        if let Err(err) = result {
+            self.rollback(access);
+            return Err(err);
+        }
+        self.log.clear();
+        self.undo.clear();
+        self.touched.clear();
+        self.begin = None;
+
+        if std::mem::take(&mut self.snapshot) {
+            if let Access::Catalog(catalog) = access {
+                database.write_snapshot(catalog)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self, access: &mut Access) {
+        while let Some(undo) = self.undo.pop() {
+            access.undo(undo);
+        }
+        self.log.clear();
+        self.touched.clear();
+        self.begin = None;
+        self.snapshot = false;
+    }
+}
+
+// Query Execution
+// Tables are locked in name order so two programs can't wait
+// on each other, read-only programs share the table locks
+fn execute_program<'a>(
+    database: &'a Database,
+    program: Program,
+    mut budget: Budget,
+) -> Result<QueryResult<'a>, Error> {
+    if program.is_structural() {
+        return database.exclusive(&mut budget, |catalog, budget| {
+            execute_with(database, &mut Access::Catalog(catalog), program, budget)
+        });
+    }
+
+    // The catalog is only locked while the tables are looked up
+    let catalog = budget.wait(|| database.tables.try_read())?;
+    let names = match program.lists_tables() {
+        true => catalog.keys().cloned().collect(),
+        false => program.tables(),
+    };
+    if !program.is_read_only() {
+        drop(catalog);
+        return database.write_tables(&names, &mut budget, |access, budget| {
+            execute_with(database, access, program, budget)
+        });
+    }
 
-pub struct Database {
-    // Hashmap from table name to records
-    tables: HashMap<String, Records>,
+    // Readers don't lock anything for the whole program, they read
+    // the versions committed before they started
+    let reader = database.begin_read(&catalog);
+    drop(catalog);
+
+    let mut result: QueryResult = {
+        let mut access = Access::Tables {
+            tables: names
+                .iter()
+                .filter_map(|name| reader.tables.get_key_value(name))
+                .map(|(name, entry)| (name.as_str(), TableGuard::Read(entry)))
+                .collect(),
+            ts: reader.ts,
+        };
+        execute_with(database, &mut access, program, &mut budget)?
+    };
+    result.reader = Some(reader);
+    Ok(result)
 }
-pub type DatabaseRef = Arc<Mutex<Database>>;
 
-const DEFAULT_TABLE: &str = "0";
+fn execute_with(
+    database: &Database,
+    access: &mut Access,
+    program: Program,
+    budget: &mut Budget,
+) -> Result<QueryResult<'static>, Error> {
+    let mut transaction = Transaction::default();
+    let mut current = 0;
+    let mut result = execute_operations(
+        database,
+        access,
+        program.operations,
+        &mut transaction,
+        budget,
+        &mut current,
+    );
 
-fn assert_stack_len(stack: &Vec<Value>, n: usize) -> Result<(), String> {
-    if stack.len() < n {
-        return Err(format!(
-            "Stack must have atleast {} value(s), current stack is {:#?}",
-            n, stack
+    if let (Ok(_), Some(begin)) = (&result, transaction.begin) {
+        current = begin;
+        result = Err(Error::query(
+            "Transaction is neither committed nor rolled back".to_owned(),
         ));
     }
-    Ok(())
-}
 
-fn get_table(table_name: String, database: &mut Database) -> Option<&mut Records> {
-    database.tables.get_mut(&table_name)
+    match result {
+        Ok(result) => {
+            transaction.commit(database, access)?;
+            Ok(result)
+        }
+        Err(err) => {
+            transaction.rollback(access);
+            Err(err.at(program.spans[current]))
+        }
+    }
 }
 
-// Query Execution
-fn execute_program(database: DatabaseRef, mut program: Program) -> Result<QueryResult, String> {
-    let database = &mut database.lock().unwrap();
-    // let records = database.tables.get_mut(DEFAULT_TABLE).unwrap();
-
+// `current` is left at the operation that failed
+fn execute_operations(
+    database: &Database,
+    access: &mut Access,
+    mut program: Vec<Operation>,
+    transaction: &mut Transaction,
+    budget: &mut Budget,
+    current: &mut usize,
+) -> Result<QueryResult<'static>, Error> {
     let mut stack = Vec::new();
-    let mut result: QueryResult = Vec::new();
+    let mut result = QueryResult::default();
     let mut i = 0;
+    let ts = access.ts();
 
     let mut it = 0;
 
     while i < program.len() {
+        *current = i;
+        budget.spend()?;
         let op = &program[i];
 
         match op {
@@ -85,31 +1319,121 @@ fn execute_program(database: DatabaseRef, mut program: Program) -> Result<QueryR
                 stack.push(value.clone());
                 i += 1;
             }
-            Operation::Set => {
+            Operation::Set | Operation::SetIfVersion => {
                 // stack must contain values
-                // Id, Key, Value
-                assert_stack_len(&stack, 3)?;
+                // Id, Key, Value and the expected version for SetIfVersion
+                let conditional = matches!(op, Operation::SetIfVersion);
+                assert_stack_len(&stack, if conditional { 4 } else { 3 })?;
 
+                let expected = match conditional {
+                    true => match stack.pop().unwrap() {
+                        Value::Int(version) if version >= 0 => Some(version as u64),
+                        _ => {
+                            return Err(Error::type_error(
+                                "Version must be a non-negative int".to_owned(),
+                            ))
+                        }
+                    },
+                    false => None,
+                };
                 let value = stack.pop().unwrap();
                 let key = match stack.pop().unwrap() {
                     Value::String(str) => str,
-                    _ => return Err("Key must be a string".to_owned()),
+                    _ => return Err(Error::type_error("Key must be a string".to_owned())),
                 };
+                check_key(&key)?;
                 let record_id = match stack.pop().unwrap() {
                     Value::Id(record_id) => record_id,
-                    val => return Err(format!("Record Id must be an id found {:#?}", val)),
+                    val => {
+                        return Err(Error::type_error(format!(
+                            "Record Id must be an id found {:#?}",
+                            val
+                        )))
+                    }
                 };
 
-                if let Some(records) = get_table(record_id.table_name.clone(), database) {
-                    intrinsics::set(records, &record_id, key, value);
-                } else {
-                    let mut records = HashMap::new();
-                    intrinsics::set(&mut records, &record_id, key, value);
-                    database
-                        .tables
-                        .insert(record_id.table_name.clone(), records);
+                // Missing records are at version 0
+                if let Some(expected) = expected {
+                    let found = access
+                        .table(&record_id.table_name)
+                        .and_then(|table| table.get(record_id.row, ts).map(|record| record.version))
+                        .unwrap_or(0);
+                    if found != expected {
+                        return Err(Error::conflict(&record_id, expected, found));
+                    }
+                }
+
+                let mutation = Mutation::Set {
+                    record_id: record_id.clone(),
+                    key,
+                    value,
+                };
+                transaction.write(access, mutation)?;
+
+                stack.push(Value::Id(record_id));
+
+                i += 1;
+            }
+            Operation::Delete => {
+                // stack must contain values
+                // Id
+                assert_stack_len(&stack, 1)?;
+
+                let record_id = match stack.pop().unwrap() {
+                    Value::Id(record_id) => record_id,
+                    _ => return Err(Error::type_error("Record Id must be an id".to_owned())),
+                };
+
+                // The table is only read locked for the check
+                let exists = match access.table(&record_id.table_name) {
+                    Some(table) => table.get(record_id.row, ts).is_some(),
+                    _ => return Err(Error::table_not_found(&record_id.table_name)),
+                };
+
+                if !exists {
+                    return Err(Error::record_not_found(&record_id));
+                }
+
+                let mutation = Mutation::Delete { record_id };
+                transaction.write(access, mutation)?;
+
+                i += 1;
+            }
+            Operation::Unset => {
+                // stack must contain values
+                // Id, Key
+                assert_stack_len(&stack, 2)?;
+
+                let key = match stack.pop().unwrap() {
+                    Value::String(str) => str,
+                    _ => return Err(Error::type_error("Key must be a string".to_owned())),
+                };
+                let record_id = match stack.pop().unwrap() {
+                    Value::Id(record_id) => record_id,
+                    _ => return Err(Error::type_error("Record Id must be an id".to_owned())),
+                };
+
+                if key.to_lowercase() == "id" {
+                    return Err(Error::query("Field `id` cannot be unset".to_owned()));
+                }
+                check_key(&key)?;
+
+                // The table is only read locked for the check
+                let exists = match access.table(&record_id.table_name) {
+                    Some(table) => table.get(record_id.row, ts).is_some(),
+                    _ => return Err(Error::table_not_found(&record_id.table_name)),
+                };
+
+                if !exists {
+                    return Err(Error::record_not_found(&record_id));
                 }
 
+                let mutation = Mutation::Unset {
+                    record_id: record_id.clone(),
+                    key,
+                };
+                transaction.write(access, mutation)?;
+
                 stack.push(Value::Id(record_id));
 
                 i += 1;
@@ -121,18 +1445,18 @@ fn execute_program(database: DatabaseRef, mut program: Program) -> Result<QueryR
 
                 let record_id = match stack.pop().unwrap() {
                     Value::Id(record_id) => record_id,
-                    _ => return Err("Record Id must be an id".to_owned()),
+                    _ => return Err(Error::type_error("Record Id must be an id".to_owned())),
                 };
 
-                let records = match get_table(record_id.table_name.clone(), database) {
+                let table = match access.table(&record_id.table_name) {
                     Some(val) => val,
-                    _ => return Err(format!("Table `{}` not found", record_id.table_name)),
+                    _ => return Err(Error::table_not_found(&record_id.table_name)),
                 };
 
-                if records.get(&record_id.row).is_some() {
-                    result.push(record_id);
+                if table.get(record_id.row, ts).is_some() {
+                    result.items.push(ResultItem::Record(record_id));
                 } else {
-                    return Err("Record not found".to_owned());
+                    return Err(Error::record_not_found(&record_id));
                 }
                 i += 1;
             }
@@ -143,19 +1467,19 @@ fn execute_program(database: DatabaseRef, mut program: Program) -> Result<QueryR
 
                 let record_id = match stack.pop().unwrap() {
                     Value::Id(record_id) => record_id,
-                    _ => return Err("Record Id must be an id".to_owned()),
+                    _ => return Err(Error::type_error("Record Id must be an id".to_owned())),
                 };
 
-                let records = match get_table(record_id.table_name.clone(), database) {
+                let table = match access.table(&record_id.table_name) {
                     Some(val) => val,
-                    _ => return Err(format!("Table `{}` not found", record_id.table_name)),
+                    _ => return Err(Error::table_not_found(&record_id.table_name)),
                 };
 
-                for row in records.keys() {
-                    result.push(RecordId {
+                for (row, _) in table.rows(ts) {
+                    result.items.push(ResultItem::Record(RecordId {
                         table_name: record_id.table_name.clone(),
-                        row: *row,
-                    });
+                        row,
+                    }));
                 }
                 i += 1;
             }
@@ -166,25 +1490,169 @@ fn execute_program(database: DatabaseRef, mut program: Program) -> Result<QueryR
 
                 let predicate = match intrinsics::match_predicate(stack.pop().unwrap()) {
                     Some(predicate) => predicate,
-                    _ => return Err("Predicate unknown".to_owned()),
+                    _ => return Err(Error::query("Predicate unknown".to_owned())),
                 };
                 let value = stack.pop().unwrap();
                 let key = match stack.pop().unwrap() {
                     Value::String(str) => str,
-                    _ => return Err("Key must be a string".to_owned()),
+                    _ => return Err(Error::type_error("Key must be a string".to_owned())),
+                };
+
+                let record_id = match stack.pop().unwrap() {
+                    Value::Id(record_id) => record_id,
+                    _ => return Err(Error::type_error("Record Id must be an id".to_owned())),
+                };
+
+                let table = match access.table(&record_id.table_name) {
+                    Some(val) => val,
+                    _ => return Err(Error::table_not_found(&record_id.table_name)),
+                };
+
+                intrinsics::filter(&table, ts, &mut result.items, key, value, predicate);
+                i += 1;
+            }
+            Operation::Tables => {
+                for (name, rows) in access.tables() {
+                    result.items.push(ResultItem::Table { name, rows });
+                }
+                i += 1;
+            }
+            Operation::DropTable => {
+                // stack must contain values
+                // Name
+                assert_stack_len(&stack, 1)?;
+
+                let table_name = match stack.pop().unwrap() {
+                    Value::String(str) => str,
+                    _ => return Err(Error::type_error("Table name must be a string".to_owned())),
+                };
+
+                if access.table(&table_name).is_none() {
+                    return Err(Error::table_not_found(&table_name));
+                }
+
+                let mutation = Mutation::DropTable { table_name };
+                transaction.write(access, mutation)?;
+
+                i += 1;
+            }
+            Operation::RenameTable => {
+                // stack must contain values
+                // From, To
+                assert_stack_len(&stack, 2)?;
+
+                let to = match stack.pop().unwrap() {
+                    Value::String(str) => str,
+                    _ => return Err(Error::type_error("Table name must be a string".to_owned())),
+                };
+                let from = match stack.pop().unwrap() {
+                    Value::String(str) => str,
+                    _ => return Err(Error::type_error("Table name must be a string".to_owned())),
+                };
+
+                if access.table(&from).is_none() {
+                    return Err(Error::table_not_found(&from));
+                }
+                if to.is_empty() || to.contains(':') {
+                    return Err(Error::query(format!("Invalid table name `{}`", to)));
+                }
+                if access.table(&to).is_some() {
+                    return Err(Error::query(format!("Table `{}` already exists", to)));
+                }
+
+                let mutation = Mutation::RenameTable { from, to };
+                transaction.write(access, mutation)?;
+
+                i += 1;
+            }
+            Operation::CreateIndex { ordered } => {
+                // stack must contain values
+                // Name, Key
+                assert_stack_len(&stack, 2)?;
+
+                let key = match stack.pop().unwrap() {
+                    Value::String(str) => str.to_lowercase(),
+                    _ => return Err(Error::type_error("Key must be a string".to_owned())),
+                };
+                let table_name = match stack.pop().unwrap() {
+                    Value::String(str) => str,
+                    _ => return Err(Error::type_error("Table name must be a string".to_owned())),
                 };
 
+                let exists = access
+                    .table(&table_name)
+                    .is_some_and(|table| table.indexes.contains_key(&key));
+                if exists {
+                    return Err(Error::query(format!(
+                        "Index on `{}.{}` already exists",
+                        table_name, key
+                    )));
+                }
+
+                let mutation = Mutation::CreateIndex {
+                    table_name,
+                    key,
+                    ordered: *ordered,
+                };
+                transaction.write(access, mutation)?;
+
+                i += 1;
+            }
+            Operation::Snapshot => {
+                if let Access::Tables { .. } = access {
+                    return Err(Error::query("Snapshot needs every table".to_owned()));
+                }
+                // Taken once the transaction commits
+                transaction.snapshot = true;
+                i += 1;
+            }
+            Operation::Begin => {
+                if transaction.begin.is_some() {
+                    return Err(Error::query("Transaction already started".to_owned()));
+                }
+                // Rolling back would otherwise undo writes made before it
+                if !transaction.log.is_empty() {
+                    return Err(Error::query(
+                        "Begin can't follow writes that aren't committed".to_owned(),
+                    ));
+                }
+                transaction.begin = Some(i);
+                i += 1;
+            }
+            Operation::Commit => {
+                if transaction.begin.is_none() {
+                    return Err(Error::query("No transaction to commit".to_owned()));
+                }
+                transaction.commit(database, access)?;
+                i += 1;
+            }
+            Operation::Rollback => {
+                if transaction.begin.is_none() {
+                    return Err(Error::query("No transaction to roll back".to_owned()));
+                }
+                transaction.rollback(access);
+                i += 1;
+            }
+            Operation::OrderBy => {
+                // stack must contain values
+                // Id, Key
+                assert_stack_len(&stack, 2)?;
+
+                let key = match stack.pop().unwrap() {
+                    Value::String(str) => str,
+                    _ => return Err(Error::type_error("Key must be a string".to_owned())),
+                };
                 let record_id = match stack.pop().unwrap() {
                     Value::Id(record_id) => record_id,
-                    _ => return Err("Record Id must be an id".to_owned()),
+                    _ => return Err(Error::type_error("Record Id must be an id".to_owned())),
                 };
 
-                let records = match get_table(record_id.table_name.clone(), database) {
+                let table = match access.table(&record_id.table_name) {
                     Some(val) => val,
-                    _ => return Err(format!("Table `{}` not found", record_id.table_name)),
+                    _ => return Err(Error::table_not_found(&record_id.table_name)),
                 };
 
-                intrinsics::filter(records, &mut result, key, value, predicate);
+                intrinsics::order_by(&table, ts, &mut result.items, key);
                 i += 1;
             }
             Operation::Drop => {
@@ -202,12 +1670,20 @@ fn execute_program(database: DatabaseRef, mut program: Program) -> Result<QueryR
 
                 let b = match stack.pop().unwrap() {
                     Value::Int(num) => num,
-                    _ => return Err("Add requires two int on stack".to_owned()),
+                    _ => {
+                        return Err(Error::type_error(
+                            "Add requires two int on stack".to_owned(),
+                        ))
+                    }
                 };
 
                 let a = match stack.pop().unwrap() {
                     Value::Int(num) => num,
-                    _ => return Err("Add requires two int on stack".to_owned()),
+                    _ => {
+                        return Err(Error::type_error(
+                            "Add requires two int on stack".to_owned(),
+                        ))
+                    }
                 };
 
                 stack.push(Value::Int(a + b));
@@ -219,17 +1695,153 @@ fn execute_program(database: DatabaseRef, mut program: Program) -> Result<QueryR
                 assert_stack_len(&stack, 2)?;
                 let b = match stack.pop().unwrap() {
                     Value::Int(num) => num,
-                    _ => return Err("Sub requires two int on stack".to_owned()),
+                    _ => {
+                        return Err(Error::type_error(
+                            "Sub requires two int on stack".to_owned(),
+                        ))
+                    }
                 };
 
                 let a = match stack.pop().unwrap() {
                     Value::Int(num) => num,
-                    _ => return Err("Sub requires two int on stack".to_owned()),
+                    _ => {
+                        return Err(Error::type_error(
+                            "Sub requires two int on stack".to_owned(),
+                        ))
+                    }
                 };
 
                 stack.push(Value::Int(a - b));
                 i += 1;
             }
+            Operation::Append => {
+                // stack must contain values
+                // Array, Value
+                assert_stack_len(&stack, 2)?;
+
+                let value = stack.pop().unwrap();
+                let mut array = match stack.pop().unwrap() {
+                    Value::Array(array) => array,
+                    _ => {
+                        return Err(Error::type_error(
+                            "Append requires an array on stack".to_owned(),
+                        ))
+                    }
+                };
+
+                array.push(value);
+                stack.push(Value::Array(array));
+                i += 1;
+            }
+            Operation::Pop => {
+                // stack must contain values
+                // Array
+                assert_stack_len(&stack, 1)?;
+
+                let mut array = match stack.pop().unwrap() {
+                    Value::Array(array) => array,
+                    _ => {
+                        return Err(Error::type_error(
+                            "Pop requires an array on stack".to_owned(),
+                        ))
+                    }
+                };
+
+                let value = match array.pop() {
+                    Some(value) => value,
+                    None => return Err(Error::query("Pop requires a non empty array".to_owned())),
+                };
+
+                stack.push(Value::Array(array));
+                stack.push(value);
+                i += 1;
+            }
+            Operation::Index => {
+                // stack must contain values
+                // Array, Index:Int
+                assert_stack_len(&stack, 2)?;
+
+                let index = match stack.pop().unwrap() {
+                    Value::Int(num) => num,
+                    _ => return Err(Error::type_error("Index must be an int".to_owned())),
+                };
+                let array = match stack.pop().unwrap() {
+                    Value::Array(array) => array,
+                    _ => {
+                        return Err(Error::type_error(
+                            "Index requires an array on stack".to_owned(),
+                        ))
+                    }
+                };
+
+                let value = match usize::try_from(index).ok().and_then(|i| array.get(i)) {
+                    Some(value) => value.clone(),
+                    None => {
+                        return Err(Error::query(format!(
+                            "Index {} out of bounds for array of length {}",
+                            index,
+                            array.len()
+                        )))
+                    }
+                };
+
+                stack.push(value);
+                i += 1;
+            }
+            Operation::Len => {
+                // stack must contain values
+                // Array
+                assert_stack_len(&stack, 1)?;
+
+                let array = match stack.pop().unwrap() {
+                    Value::Array(array) => array,
+                    _ => {
+                        return Err(Error::type_error(
+                            "Len requires an array on stack".to_owned(),
+                        ))
+                    }
+                };
+
+                stack.push(Value::Int(array.len() as i64));
+                i += 1;
+            }
+            Operation::Link => {
+                // stack must contain values
+                // Id
+                assert_stack_len(&stack, 1)?;
+
+                let record_id = match stack.pop().unwrap() {
+                    Value::Id(record_id) => record_id,
+                    _ => return Err(Error::type_error("Link requires an id on stack".to_owned())),
+                };
+
+                stack.push(Value::RecordLink(record_id));
+                i += 1;
+            }
+            Operation::Expand => {
+                // stack must contain values
+                // Depth:Int
+                assert_stack_len(&stack, 1)?;
+
+                let depth = match stack.pop().unwrap() {
+                    Value::Int(num) if num >= 0 => num as u64,
+                    _ => {
+                        return Err(Error::type_error(
+                            "Expand requires a positive int on stack".to_owned(),
+                        ))
+                    }
+                };
+
+                let max = budget.limits.max_expand_depth;
+                if max > 0 && depth > max {
+                    return Err(Error::query(format!(
+                        "Expand depth {} is above the limit of {}",
+                        depth, max
+                    )));
+                }
+                result.depth = depth as usize;
+                i += 1;
+            }
             Operation::It => {
                 stack.push(Value::Int(it));
                 i += 1;
@@ -255,124 +1867,376 @@ fn execute_program(database: DatabaseRef, mut program: Program) -> Result<QueryR
     Ok(result)
 }
 
-fn results_to_json(database: DatabaseRef, result: QueryResult) -> String {
-    let database = database.lock().unwrap();
+// `path` holds the records being printed, a link back
+// to one of them is printed as an id to break the cycle
+fn value_to_json(
+    json: &mut JsonWriter,
+    database: &Database,
+    result: &QueryResult,
+    value: &Value,
+    depth: usize,
+    path: &mut Vec<RecordId>,
+) {
+    match value {
+        Value::Id(record_id) => {
+            json.string(&format!("@{}:{}", record_id.table_name, record_id.row))
+        }
+        Value::Null => json.null(),
+        Value::Bool(val) => json.bool(*val),
+        Value::Int(val) => json.int(*val),
+        Value::Float(val) => json.float(*val),
+        Value::String(val) => json.string(val),
+        Value::Array(values) => {
+            json.begin_array();
+            for value in values {
+                value_to_json(json, database, result, value, depth, path);
+            }
+            json.end_array();
+        }
+        Value::RecordLink(record_id) => {
+            // Recursively print the document
+            let record = match depth > 0 && !path.contains(record_id) {
+                true => result.get(database, record_id),
+                false => None,
+            };
+
+            match record {
+                Some(record) => {
+                    record_to_json(json, database, result, record_id, &record, depth - 1, path)
+                }
+                _ => json.string(&format!("@{}:{}", record_id.table_name, record_id.row)),
+            }
+        }
+    }
+}
+
+fn record_to_json(
+    json: &mut JsonWriter,
+    database: &Database,
+    result: &QueryResult,
+    record_id: &RecordId,
+    record: &Record,
+    depth: usize,
+    path: &mut Vec<RecordId>,
+) {
+    path.push(record_id.clone());
 
-    let mut output = String::new();
-    output.push_str("{\n\"message\":\"OK\",\n\"data\": [\n");
+    json.begin_object();
+    for (key, value) in &record.fields {
+        json.key(key);
+        value_to_json(json, database, result, value, depth, path);
+    }
+    json.key(VERSION_KEY);
+    json.int(record.version as i64);
+    json.end_object();
 
-    for (row, id) in result.iter().enumerate() {
-        let records = database.tables.get(&id.table_name).unwrap();
-        let record = records.get(&id.row).unwrap();
+    path.pop();
+}
 
-        output.push_str("{\n");
+fn results_to_json(database: &Database, result: QueryResult) -> String {
+    let mut json = JsonWriter::new();
+    json.begin_object();
+    json.key("message");
+    json.string("OK");
+    json.key("data");
+    json.begin_array();
 
-        for (i, (key, value)) in record.fields.iter().enumerate() {
-            match value {
-                Value::Id(record_id) => {
-                    output.push_str(&format!(
-                        "\"{}\":\"{}:{}\"",
-                        key, record_id.table_name, record_id.row
-                    ));
+    for item in &result.items {
+        match item {
+            ResultItem::Record(id) => {
+                // The record may have been deleted later in the program.
+                // Records are copied one at a time so rendering never
+                // holds more than one table lock
+                if let Some(record) = result.get(database, id) {
+                    record_to_json(
+                        &mut json,
+                        database,
+                        &result,
+                        id,
+                        &record,
+                        result.depth,
+                        &mut Vec::new(),
+                    );
                 }
-                Value::Int(val) => {
-                    output.push_str(&format!("\"{}\":{}", key, val));
-                }
-                Value::Float(val) => {
-                    output.push_str(&format!("\"{}\":{}", key, val));
-                }
-                Value::String(val) => {
-                    output.push_str(&format!("\"{}\":\"{}\"", key, val));
-                } // Recurvivly print the document
-                  // Value::RecordLink(_) => {
-                  // let record = records.get(id).unwrap();
-                  // todo!()
-                  // }
             }
-            if i != record.fields.len() - 1 {
-                output.push(',');
+            ResultItem::Table { name, rows } => {
+                json.begin_object();
+                json.key("name");
+                json.string(name);
+                json.key("rows");
+                json.int(*rows as i64);
+                json.end_object();
             }
-            output.push('\n');
         }
+    }
 
-        if row == result.len() - 1 {
-            output.push('}');
-        } else {
-            output.push_str("},\n");
-        }
+    json.end_array();
+    json.end_object();
+    json.finish()
+}
+
+fn queries_to_json(queries: &[QueryInfo]) -> String {
+    let mut json = JsonWriter::new();
+    json.begin_object();
+    json.key("message");
+    json.string("OK");
+    json.key("data");
+    json.begin_array();
+
+    for query in queries {
+        json.begin_object();
+        json.key("id");
+        json.int(query.id as i64);
+        json.key("query");
+        json.string(&query.query);
+        json.key("elapsed_ms");
+        json.int(query.elapsed.as_millis() as i64);
+        json.end_object();
     }
-    output.push_str("]\n}\n");
-    output
+
+    json.end_array();
+    json.end_object();
+    json.finish()
 }
 
-fn report_err(err: String, mut stream: TcpStream) {
-    let json = format!("{{\"message\":\"{}\"}}", err);
+// `query` is the query the error comes from, when there is one
+fn error_to_json(err: &Error, query: Option<&str>) -> String {
+    let mut json = JsonWriter::new();
+    json.begin_object();
+    json.key("message");
+    json.string(&err.message());
+    json.key("code");
+    json.string(err.code());
+    if let Some((line, col)) = err.position() {
+        json.key("line");
+        json.int(line as i64);
+        json.key("column");
+        json.int(col as i64);
+    }
+    if let Some(snippet) = query.and_then(|query| err.snippet(query)) {
+        json.key("snippet");
+        json.string(&snippet);
+    }
+    json.end_object();
+    json.finish()
+}
 
-    let response = format!(
-        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n{}\r\n\r\n{}",
-        json.len(),
-        "Content-Type: application/json",
-        json
-    );
+fn report_err(err: Error, query: Option<&str>, mut stream: TcpStream) {
+    let json = error_to_json(&err, query);
+    let _ = http::write_response(&mut stream, err.status(), &json);
 
-    stream.write_all(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+    error!("{}", err);
+}
+
+// Clients may lower the server's limits for their query with
+// `X-Max-Instructions` and `X-Query-Timeout` (seconds), not raise them
+fn request_limits(request: &http::Request, limits: Limits) -> Result<Limits, Error> {
+    let header = |name: &str| match request.header(name) {
+        Some(value) => match value.parse::<u64>() {
+            Ok(val) => Ok(val),
+            Err(_) => Err(Error::BadRequest(format!(
+                "Invalid value `{}` for header {}",
+                value, name
+            ))),
+        },
+        None => Ok(0),
+    };
+
+    let requested = Limits {
+        max_instructions: header("x-max-instructions")?,
+        timeout: Duration::from_secs(header("x-query-timeout")?),
+        max_expand_depth: 0,
+    };
+    Ok(limits.min(requested))
+}
 
-    println!("\x1b[0;31mError : {}\x1b[0m", err);
+// `GET /queries` lists the running queries and
+// `DELETE /queries/{id}` cancels one of them
+fn manage_queries(request: &http::Request, registry: &Registry) -> Result<String, Error> {
+    let id = request
+        .path
+        .strip_prefix("/queries")
+        .and_then(|rest| rest.strip_prefix('/'));
+
+    match (request.method.as_str(), id) {
+        ("GET", None) => Ok(queries_to_json(&registry.list())),
+        ("DELETE", Some(id)) => {
+            let id = match id.parse() {
+                Ok(val) => val,
+                Err(_) => return Err(Error::BadRequest(format!("Invalid query id `{}`", id))),
+            };
+            match registry.cancel(id) {
+                Some(query) => Ok(queries_to_json(&[query])),
+                None => Err(Error::QueryNotFound(id)),
+            }
+        }
+        _ => Err(Error::BadRequest(format!(
+            "Unsupported request {} {}",
+            request.method, request.path
+        ))),
+    }
 }
 
-fn handle_query(database: DatabaseRef, mut stream: TcpStream) -> impl FnOnce() + Send + 'static {
+fn handle_query(
+    database: DatabaseRef,
+    registry: Arc<Registry>,
+    mut stream: TcpStream,
+    max_body_size: usize,
+    limits: Limits,
+) -> impl FnOnce() + Send + 'static {
     move || {
-        let buf_reader = BufReader::new(&mut stream);
-        let program = match query::parse_tcp(buf_reader) {
+        let mut buf_reader = BufReader::new(&mut stream);
+        let request = match http::read_request(&mut buf_reader, max_body_size) {
             Ok(val) => val,
+            Err(http::HttpError::Closed) => return,
             Err(err) => {
-                report_err(err, stream);
+                report_err(err.into(), None, stream);
                 return;
             }
         };
 
-        let result = match execute_program(Arc::clone(&database), program) {
+        // Any other path is a query
+        if request.path == "/queries" || request.path.starts_with("/queries/") {
+            debug!("{} {}", request.method, request.path);
+            match manage_queries(&request, &registry) {
+                Ok(json) => {
+                    let _ = http::write_response(&mut stream, 200, &json);
+                }
+                Err(err) => report_err(err, None, stream),
+            }
+            return;
+        }
+
+        let limits = match request_limits(&request, limits) {
             Ok(val) => val,
             Err(err) => {
-                report_err(err, stream);
+                report_err(err, None, stream);
+                return;
+            }
+        };
+
+        let body = match String::from_utf8(request.body) {
+            Ok(val) => val,
+            Err(_) => {
+                report_err(
+                    Error::BadRequest("Query is not valid UTF-8".to_owned()),
+                    None,
+                    stream,
+                );
                 return;
             }
         };
 
-        let json = results_to_json(Arc::clone(&database), result);
-        let response = format!(
-            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n{}\r\n\r\n{}",
-            json.len(),
-            "Content-Type: application/json",
-            json
+        // Listed until the response is sent
+        let registration = registry.register(&body);
+
+        debug!("{} {}", request.method, request.path);
+        debug!(
+            "Executing query {}: \x1b[1;95m{}\x1b[0m",
+            registration.id,
+            body.trim()
         );
 
-        stream.write_all(response.as_bytes()).unwrap();
-        stream.flush().unwrap();
+        let program = match query::parse(&body) {
+            Ok(val) => val,
+            Err(err) => {
+                report_err(err, Some(&body), stream);
+                return;
+            }
+        };
+
+        let mut budget = Budget::new(limits);
+        budget.cancelled = Some(Arc::clone(&registration.cancelled));
+
+        let result = match execute_program(&database, program, budget) {
+            Ok(val) => val,
+            Err(err) => {
+                report_err(err, Some(&body), stream);
+                return;
+            }
+        };
+
+        let json = results_to_json(&database, result);
+        let _ = http::write_response(&mut stream, 200, &json);
     }
 }
 
-pub fn run() -> Result<(), String> {
+pub fn run(config: ServerConfig) -> Result<(), Error> {
+    log::set_level(config.log_level);
+
     // Database
-    let database = Database {
-        tables: HashMap::from([(DEFAULT_TABLE.to_owned(), HashMap::new())]),
-    };
-    let database = Arc::new(Mutex::new(database));
+    let database = Database::open(Config {
+        data_dir: Some(config.data_dir.clone()),
+    })?;
+    let database = Arc::new(database);
 
-    let listener = match TcpListener::bind("127.0.0.1:1234") {
+    let address = SocketAddr::new(config.bind, config.port);
+    let listener = match TcpListener::bind(address) {
         Ok(val) => val,
-        Err(err) => return Err(format!("{}", err)),
+        Err(err) => return Err(Error::io(&format!("Unable to bind {}", address), err)),
     };
 
-    let pool = ThreadPool::new(4);
+    let interval = config.snapshot_interval;
+    if interval > 0 {
+        let database = Arc::clone(&database);
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(interval));
+            if let Err(err) = database.snapshot() {
+                error!("{}", err);
+            }
+        });
+    }
+
+    let pool = ThreadPool::new(config.workers);
+    let registry = Arc::new(Registry::default());
+
+    // Non blocking so the loop notices a shutdown request
+    // without waiting for the next connection
+    if let Err(err) = listener.set_nonblocking(true) {
+        return Err(Error::io("Unable to configure listener", err));
+    }
+    signal::install();
+
+    info!("Database is listening to http://{}", address);
 
-    println!("Database is listening to http://localhost:1234");
+    while !signal::shutdown_requested() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(err) = stream.set_nonblocking(false) {
+                    error!("Unable to configure connection: {}", err);
+                    continue;
+                }
+                pool.execute(handle_query(
+                    Arc::clone(&database),
+                    Arc::clone(&registry),
+                    stream,
+                    config.max_request_size,
+                    config.limits(),
+                ));
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_INTERVAL);
+            }
+            Err(err) => error!("Unable to accept connection: {}", err),
+        }
+    }
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        pool.execute(handle_query(Arc::clone(&database), stream));
+    info!("Shutting down, waiting for running queries");
+    if !pool.shutdown(Duration::from_secs(config.shutdown_timeout)) {
+        // Their mutations are not acknowledged yet, the
+        // log replay only keeps what was committed
+        let cancelled = registry.cancel_all();
+        let err = io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{} queries still running were cancelled", cancelled),
+        );
+        return Err(Error::io("Shutdown timed out", err));
     }
 
+    // Every commit is already synced, the snapshot
+    // only saves replaying the log on the next start
+    database.snapshot()?;
+    info!("Shut down cleanly");
+
     Ok(())
 }
@@ -1,7 +1,24 @@
 use std::process::exit;
 
+use real_db::ServerConfig;
+
 fn main() {
-    if let Err(e) = real_db::run() {
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        print!("{}", real_db::USAGE);
+        return;
+    }
+
+    let config = match ServerConfig::from_args(args) {
+        Ok(val) => val,
+        Err(e) => {
+            println!("Error: {}", e);
+            println!("Run with --help to see the available options");
+            exit(1);
+        }
+    };
+
+    if let Err(e) = real_db::run(config) {
         println!("Error: {}", e);
         exit(1);
     };
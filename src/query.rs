@@ -1,19 +1,24 @@
-use std::{
-    io::{BufRead, BufReader, Read},
-    net::TcpStream,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::collections::BTreeSet;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use random::Source;
 
-use crate::{RecordId, Value};
+use crate::{error::Error, RecordId, Value};
+
+// Position of a token in the query, `len` is in characters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+}
 
 #[derive(Debug)]
 pub struct Token {
+    // Contents of string literals are stored unescaped
     word: String,
     kind: TokenKind,
-    line: usize,
-    col: usize,
+    span: Span,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -21,11 +26,26 @@ pub enum TokenKind {
     Plus,
     Minus,
     Set,
+    SetIfVersion,
+    Delete,
+    Unset,
     Select,
     SelectAll,
     Filter,
     Drop,
+    Tables,
+    DropTable,
+    RenameTable,
+    CreateIndex,
+    CreateOrderedIndex,
+    OrderBy,
+    Snapshot,
+    Begin,
+    Commit,
+    Rollback,
     Id,
+    Null,
+    Bool,
     Int,
     Float,
     String,
@@ -35,6 +55,14 @@ pub enum TokenKind {
     Do,
     End,
     SemiColon,
+    OpenBracket,
+    CloseBracket,
+    Append,
+    Pop,
+    Index,
+    Len,
+    Link,
+    Expand,
 }
 
 fn match_token_kind(word: &str) -> TokenKind {
@@ -43,20 +71,39 @@ fn match_token_kind(word: &str) -> TokenKind {
         "+" => TokenKind::Plus,
         "-" => TokenKind::Minus,
         "set" => TokenKind::Set,
+        "set_if_version" => TokenKind::SetIfVersion,
+        "delete" => TokenKind::Delete,
+        "unset" => TokenKind::Unset,
         "select" => TokenKind::Select,
         "select_all" => TokenKind::SelectAll,
         "filter" => TokenKind::Filter,
         "drop" => TokenKind::Drop,
+        "tables" => TokenKind::Tables,
+        "drop_table" => TokenKind::DropTable,
+        "rename_table" => TokenKind::RenameTable,
+        "create_index" => TokenKind::CreateIndex,
+        "create_ordered_index" => TokenKind::CreateOrderedIndex,
+        "order_by" => TokenKind::OrderBy,
+        "snapshot" => TokenKind::Snapshot,
+        "begin" => TokenKind::Begin,
+        "commit" => TokenKind::Commit,
+        "rollback" => TokenKind::Rollback,
         "range" => TokenKind::Range,
         "it" => TokenKind::It,
         "do" => TokenKind::Do,
         "end" => TokenKind::End,
         ";" => TokenKind::SemiColon,
+        "null" => TokenKind::Null,
+        "true" | "false" => TokenKind::Bool,
+        "[" => TokenKind::OpenBracket,
+        "]" => TokenKind::CloseBracket,
+        "append" => TokenKind::Append,
+        "pop" => TokenKind::Pop,
+        "index" => TokenKind::Index,
+        "len" => TokenKind::Len,
+        "link" => TokenKind::Link,
+        "expand" => TokenKind::Expand,
         _ => {
-            if word.starts_with('\"') && word.ends_with('\"') {
-                return TokenKind::String;
-            }
-
             if word.parse::<i64>().is_ok() {
                 return TokenKind::Int;
             }
@@ -75,63 +122,179 @@ fn match_token_kind(word: &str) -> TokenKind {
     }
 }
 
-fn tokenize(contents: String) -> Result<Vec<Token>, String> {
-    fn flush_token(tokens: &mut Vec<Token>, word: &mut String, line: usize, col: usize) {
-        let w = word.trim();
-
-        if w.is_empty() {
-            return;
-        }
+struct Lexer<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+    col: usize,
+}
 
-        tokens.push(Token {
-            word: w.to_owned(),
-            kind: match_token_kind(w),
-            line,
-            col,
-        });
+impl Lexer<'_> {
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
 
-        word.clear();
+    // Columns count characters, not bytes
+    fn next(&mut self) -> Option<char> {
+        let ch = self.chars.next()?;
+        if ch == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(ch)
     }
 
-    let mut tokens = Vec::new();
-    let mut word = String::new();
+    fn error(&self, message: String, line: usize, col: usize) -> Error {
+        Error::ParseError {
+            message,
+            span: Span { line, col, len: 1 },
+        }
+    }
 
-    let mut line = 1;
-    let mut col = 1;
+    // Span of a token starting at `line` and `col` that ends
+    // here, tokens spanning lines are only underlined at the start
+    fn span(&self, line: usize, col: usize) -> Span {
+        let len = if self.line == line { self.col - col } else { 1 };
+        Span { line, col, len }
+    }
 
-    let mut is_str = false;
-    let mut is_comment = false;
+    // Reads a string literal, the opening quote is already consumed
+    fn string(&mut self, line: usize, col: usize) -> Result<String, Error> {
+        let mut str = String::new();
 
-    for ch in contents.chars() {
-        match ch {
-            ' ' | '\n' | '\r' => {
-                if !is_str {
-                    flush_token(&mut tokens, &mut word, line, col);
+        loop {
+            let (esc_line, esc_col) = (self.line, self.col);
+            let ch = match self.next() {
+                Some(ch) => ch,
+                None => {
+                    return Err(self.error("Unterminated string".to_owned(), line, col));
                 }
-
-                if ch == '\n' {
-                    is_comment = false;
-                    line += 1;
-                    col = 0;
+            };
+
+            match ch {
+                '"' => return Ok(str),
+                '\\' => {
+                    let escaped = match self.next() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('u') => self.unicode(esc_line, esc_col)?,
+                        Some(ch) => {
+                            return Err(self.error(
+                                format!("Unknown escape sequence `\\{}`", ch.escape_default()),
+                                esc_line,
+                                esc_col,
+                            ))
+                        }
+                        None => {
+                            return Err(self.error("Unterminated string".to_owned(), line, col))
+                        }
+                    };
+                    str.push(escaped);
                 }
+                ch => str.push(ch),
             }
-            '#' => is_comment = true,
-            '\"' => {
-                is_str = !is_str;
+        }
+    }
+
+    // Reads the `{..}` part of a `\u{..}` escape
+    fn unicode(&mut self, line: usize, col: usize) -> Result<char, Error> {
+        let invalid = |lexer: &Lexer| {
+            lexer.error(
+                "Invalid unicode escape, it should be like \\u{1F600}".to_owned(),
+                line,
+                col,
+            )
+        };
+
+        if self.next() != Some('{') {
+            return Err(invalid(self));
+        }
+
+        let mut hex = String::new();
+        loop {
+            match self.next() {
+                Some('}') => break,
+                Some(ch) if ch.is_ascii_hexdigit() && hex.len() < 6 => hex.push(ch),
+                _ => return Err(invalid(self)),
+            }
+        }
+
+        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+            Some(ch) => Ok(ch),
+            None => Err(invalid(self)),
+        }
+    }
+}
+
+// Characters that end a word
+fn is_delimiter(ch: char) -> bool {
+    ch.is_whitespace() || matches!(ch, '"' | '#' | '[' | ']' | ';')
+}
+
+fn tokenize(contents: &str) -> Result<Vec<Token>, Error> {
+    let mut lexer = Lexer {
+        chars: contents.chars().peekable(),
+        line: 1,
+        col: 1,
+    };
+
+    let mut tokens = Vec::new();
+
+    while let Some(ch) = lexer.peek() {
+        let (line, col) = (lexer.line, lexer.col);
+
+        if ch.is_whitespace() {
+            lexer.next();
+            continue;
+        }
+
+        // Comments run until the end of the line
+        if ch == '#' {
+            while lexer.peek().is_some_and(|ch| ch != '\n') {
+                lexer.next();
             }
-            _ => {}
+            continue;
         }
 
-        if !is_comment {
+        if ch == '"' {
+            lexer.next();
+            let word = lexer.string(line, col)?;
+            tokens.push(Token {
+                word,
+                kind: TokenKind::String,
+                span: lexer.span(line, col),
+            });
+            continue;
+        }
+
+        // Brackets and semicolons are tokens on their
+        // own even when not separated by whitespace
+        let mut word = String::new();
+        if matches!(ch, '[' | ']' | ';') {
             word.push(ch);
-            col += 1;
+            lexer.next();
+        } else {
+            while let Some(ch) = lexer.peek().filter(|ch| !is_delimiter(*ch)) {
+                word.push(ch);
+                lexer.next();
+            }
         }
+
+        tokens.push(Token {
+            kind: match_token_kind(&word),
+            word,
+            span: lexer.span(line, col),
+        });
     }
 
-    flush_token(&mut tokens, &mut word, line, col);
     Ok(tokens)
 }
 
+#[derive(Debug)]
 pub enum Operation {
     // Start and end labels for query
     Start,
@@ -139,13 +302,36 @@ pub enum Operation {
     Push(Value),
     // Instricts
     Set,
+    // Set, failing if the record isn't at the expected version
+    SetIfVersion,
+    Delete,
+    Unset,
     Select,
     SelectAll,
     Filter,
     Drop,
+    Tables,
+    DropTable,
+    RenameTable,
+    CreateIndex { ordered: bool },
+    OrderBy,
+    Snapshot,
+    // Transaction boundaries
+    Begin,
+    Commit,
+    Rollback,
     Add,
     Subtract,
     It,
+    // Array words
+    Append,
+    Pop,
+    Index,
+    Len,
+    // Turns an id into a link
+    Link,
+    // Sets how deep links are expanded in the output
+    Expand,
     // Starts a range scope
     // Decide weather to jump to end or fallthrough
     Range { value: i64, end: usize },
@@ -153,13 +339,117 @@ pub enum Operation {
     Jump(usize),
 }
 
-pub type Program = Vec<Operation>;
+impl Operation {
+    // Operations changing the database or its storage
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Operation::Set
+                | Operation::SetIfVersion
+                | Operation::Delete
+                | Operation::Unset
+                | Operation::DropTable
+                | Operation::RenameTable
+                | Operation::CreateIndex { .. }
+                | Operation::Snapshot
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Program {
+    pub operations: Vec<Operation>,
+    // Where each operation comes from in the query,
+    // used to point runtime errors at it
+    pub spans: Vec<Span>,
+}
+
+impl Program {
+    pub fn push(&mut self, operation: Operation, span: Span) {
+        self.operations.push(operation);
+        self.spans.push(span);
+    }
+
+    fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        !self.operations.iter().any(Operation::is_write)
+    }
+
+    // Ids can only come from literals, so this is every table
+    // the program can touch, sorted in the order they are locked
+    pub fn tables(&self) -> BTreeSet<String> {
+        fn collect(value: &Value, tables: &mut BTreeSet<String>) {
+            match value {
+                Value::Id(record_id) | Value::RecordLink(record_id) => {
+                    tables.insert(record_id.table_name.clone());
+                }
+                Value::Array(values) => values.iter().for_each(|value| collect(value, tables)),
+                _ => {}
+            }
+        }
+
+        let mut tables = BTreeSet::new();
+        for operation in &self.operations {
+            if let Operation::Push(value) = operation {
+                collect(value, &mut tables);
+            }
+        }
+        tables
+    }
+
+    // Operations naming tables with strings, or working on all of
+    // them, need every table and exclusive access to the list of tables
+    pub fn is_structural(&self) -> bool {
+        self.operations.iter().any(|operation| {
+            matches!(
+                operation,
+                Operation::DropTable
+                    | Operation::RenameTable
+                    | Operation::CreateIndex { .. }
+                    | Operation::Snapshot
+            )
+        })
+    }
+
+    // `Tables` reads every table
+    pub fn lists_tables(&self) -> bool {
+        self.operations
+            .iter()
+            .any(|operation| matches!(operation, Operation::Tables))
+    }
+}
+
+// Values inside an array literal are collected
+// instead of being pushed on the stack
+fn push_value(program: &mut Program, arrays: &mut [Vec<Value>], value: Value, span: Span) {
+    match arrays.last_mut() {
+        Some(array) => array.push(value),
+        None => program.push(Operation::Push(value), span),
+    }
+}
+
+fn parse_error(message: String, token: &Token) -> Error {
+    Error::ParseError {
+        message,
+        span: token.span,
+    }
+}
 
-pub fn parse(contents: String) -> Result<Program, String> {
+pub fn parse(contents: &str) -> Result<Program, Error> {
     let tokens = tokenize(contents)?;
 
-    let mut program = vec![Operation::Start];
+    let first = Span {
+        line: 1,
+        col: 1,
+        len: 0,
+    };
+    let mut program = Program::default();
+    program.push(Operation::Start, first);
     let mut scopes = Vec::new();
+    let mut arrays = Vec::new();
 
     let start = SystemTime::now();
     let since_the_epoch = start.duration_since(UNIX_EPOCH).unwrap();
@@ -169,18 +459,37 @@ pub fn parse(contents: String) -> Result<Program, String> {
     while i < tokens.len() {
         let token = &tokens[i];
 
+        if !arrays.is_empty()
+            && !matches!(
+                token.kind,
+                TokenKind::Id
+                    | TokenKind::Null
+                    | TokenKind::Bool
+                    | TokenKind::String
+                    | TokenKind::Int
+                    | TokenKind::Float
+                    | TokenKind::OpenBracket
+                    | TokenKind::CloseBracket
+            )
+        {
+            return Err(parse_error(
+                format!("Unexpected `{}` inside array", token.word.escape_default()),
+                token,
+            ));
+        }
+
         match token.kind {
             TokenKind::Id => {
                 let parts: Vec<_> = token.word.split(':').collect();
 
                 if parts.len() != 2 {
-                    return Err(format!(
-                        "Unexpected id format at line {}:{}. It should be like @table_name:1234",
-                        token.line, token.col
+                    return Err(parse_error(
+                        "Unexpected id format. It should be like @table_name:1234".to_owned(),
+                        token,
                     ));
                 }
 
-                let table_name = parts[0];
+                let table_name = parts[0].strip_prefix('@').unwrap();
                 let r = parts[1];
                 let row = match r {
                     "_" => rng.read_u64(),
@@ -188,58 +497,115 @@ pub fn parse(contents: String) -> Result<Program, String> {
                         if let Ok(n) = r.parse::<u64>() {
                             n
                         } else {
-                            return Err(format!(
-                        "Unexpected id format at line {}:{}. It should be like :table_name:1234",
-                        token.line, token.col));
+                            return Err(parse_error(
+                                "Unexpected id format. It should be like @table_name:1234"
+                                    .to_owned(),
+                                token,
+                            ));
                         }
                     }
                 };
 
-                program.push(Operation::Push(Value::Id(RecordId {
-                    table_name: table_name.to_owned(),
-                    row,
-                })));
+                push_value(
+                    &mut program,
+                    &mut arrays,
+                    Value::Id(RecordId {
+                        table_name: table_name.to_owned(),
+                        row,
+                    }),
+                    token.span,
+                );
             }
-            TokenKind::String => {
-                let mut w = token.word.strip_prefix('\"').unwrap();
-                w = w.strip_suffix('\"').unwrap();
-
-                program.push(Operation::Push(Value::String(w.to_owned())));
+            TokenKind::String => push_value(
+                &mut program,
+                &mut arrays,
+                Value::String(token.word.clone()),
+                token.span,
+            ),
+            TokenKind::Null => push_value(&mut program, &mut arrays, Value::Null, token.span),
+            TokenKind::Bool => push_value(
+                &mut program,
+                &mut arrays,
+                Value::Bool(token.word.to_lowercase() == "true"),
+                token.span,
+            ),
+            TokenKind::Int => push_value(
+                &mut program,
+                &mut arrays,
+                Value::Int(token.word.parse().unwrap()),
+                token.span,
+            ),
+            TokenKind::Float => push_value(
+                &mut program,
+                &mut arrays,
+                Value::Float(token.word.parse().unwrap()),
+                token.span,
+            ),
+            TokenKind::OpenBracket => arrays.push(Vec::new()),
+            TokenKind::CloseBracket => {
+                if let Some(array) = arrays.pop() {
+                    push_value(&mut program, &mut arrays, Value::Array(array), token.span);
+                } else {
+                    return Err(parse_error(
+                        "Unexpected ] without matching [".to_owned(),
+                        token,
+                    ));
+                }
             }
-            TokenKind::Int => {
-                program.push(Operation::Push(Value::Int(token.word.parse().unwrap())))
+            TokenKind::Append => program.push(Operation::Append, token.span),
+            TokenKind::Pop => program.push(Operation::Pop, token.span),
+            TokenKind::Index => program.push(Operation::Index, token.span),
+            TokenKind::Len => program.push(Operation::Len, token.span),
+            TokenKind::Link => program.push(Operation::Link, token.span),
+            TokenKind::Expand => program.push(Operation::Expand, token.span),
+            TokenKind::Set => program.push(Operation::Set, token.span),
+            TokenKind::SetIfVersion => program.push(Operation::SetIfVersion, token.span),
+            TokenKind::Delete => program.push(Operation::Delete, token.span),
+            TokenKind::Unset => program.push(Operation::Unset, token.span),
+            TokenKind::Select => program.push(Operation::Select, token.span),
+            TokenKind::SelectAll => program.push(Operation::SelectAll, token.span),
+            TokenKind::Filter => program.push(Operation::Filter, token.span),
+            TokenKind::Drop => program.push(Operation::Drop, token.span),
+            TokenKind::Tables => program.push(Operation::Tables, token.span),
+            TokenKind::DropTable => program.push(Operation::DropTable, token.span),
+            TokenKind::RenameTable => program.push(Operation::RenameTable, token.span),
+            TokenKind::CreateIndex => {
+                program.push(Operation::CreateIndex { ordered: false }, token.span)
             }
-            TokenKind::Float => {
-                program.push(Operation::Push(Value::Float(token.word.parse().unwrap())))
+            TokenKind::CreateOrderedIndex => {
+                program.push(Operation::CreateIndex { ordered: true }, token.span)
             }
-            TokenKind::Set => program.push(Operation::Set),
-            TokenKind::Select => program.push(Operation::Select),
-            TokenKind::SelectAll => program.push(Operation::SelectAll),
-            TokenKind::Filter => program.push(Operation::Filter),
-            TokenKind::Drop => program.push(Operation::Drop),
-            TokenKind::Plus => program.push(Operation::Add),
-            TokenKind::Minus => program.push(Operation::Subtract),
+            TokenKind::OrderBy => program.push(Operation::OrderBy, token.span),
+            TokenKind::Snapshot => program.push(Operation::Snapshot, token.span),
+            TokenKind::Begin => program.push(Operation::Begin, token.span),
+            TokenKind::Commit => program.push(Operation::Commit, token.span),
+            TokenKind::Rollback => program.push(Operation::Rollback, token.span),
+            TokenKind::Plus => program.push(Operation::Add, token.span),
+            TokenKind::Minus => program.push(Operation::Subtract, token.span),
             TokenKind::Range => {
                 i += 1;
-                let next_token = &tokens[i];
+                let next_token = match tokens.get(i) {
+                    Some(val) => val,
+                    None => return Err(parse_error("Expected int after range".to_owned(), token)),
+                };
                 if next_token.kind != TokenKind::Int {
-                    return Err(format!(
-                        "Expected int at not {} line {}:{}",
-                        next_token.word, next_token.line, next_token.col
+                    return Err(parse_error(
+                        format!("Expected int not {}", next_token.word),
+                        next_token,
                     ));
                 }
                 let value: i64 = next_token.word.parse().unwrap();
 
                 scopes.push(program.len());
-                program.push(Operation::Range { value, end: 0 });
+                program.push(Operation::Range { value, end: 0 }, token.span);
             }
             TokenKind::It => {
                 if scopes.last().is_some() {
-                    program.push(Operation::It);
+                    program.push(Operation::It, token.span);
                 } else {
-                    return Err(format!(
-                        "Unexpected end without matching do line {}:{}",
-                        token.line, token.col
+                    return Err(parse_error(
+                        "Unexpected it outside of a range".to_owned(),
+                        token,
                     ));
                 }
             }
@@ -247,64 +613,50 @@ pub fn parse(contents: String) -> Result<Program, String> {
             TokenKind::End => {
                 if let Some(pos) = scopes.pop() {
                     let end = program.len() + 1;
-                    program[pos] = match program[pos] {
+                    program.operations[pos] = match program.operations[pos] {
                         Operation::Range { value, .. } => Operation::Range { value, end },
                         _ => todo!(),
                     };
 
-                    program.push(Operation::Jump(pos));
+                    program.push(Operation::Jump(pos), token.span);
                 } else {
-                    return Err(format!(
-                        "Unexpected end without matching do line {}:{}",
-                        token.line, token.col
+                    return Err(parse_error(
+                        "Unexpected end without matching do".to_owned(),
+                        token,
                     ));
                 }
             }
             TokenKind::SemiColon => {}
             TokenKind::Word => {
-                return Err(format!(
-                    "Unexpected word `{}` line {}:{}",
-                    token.word.escape_default(),
-                    token.line,
-                    token.col
+                return Err(parse_error(
+                    format!("Unexpected word `{}`", token.word.escape_default()),
+                    token,
                 ));
             }
         }
 
         i += 1;
     }
-    program.push(Operation::End);
-
-    Ok(program)
-}
-
-pub fn parse_tcp(mut buf_reader: BufReader<&mut TcpStream>) -> Result<Program, String> {
-    // Reading Headers
-    let mut request_line = String::new();
-    buf_reader.read_line(&mut request_line).unwrap();
-
-    loop {
-        let mut header_line = String::new();
-        buf_reader.read_line(&mut header_line).unwrap();
 
-        if header_line.trim() == "" {
-            break;
+    if let Some(token) = tokens.last() {
+        if !arrays.is_empty() {
+            return Err(parse_error(
+                "Unexpected end of query inside array".to_owned(),
+                token,
+            ));
+        }
+        if !scopes.is_empty() {
+            return Err(parse_error(
+                "Unexpected end of query, range is missing end".to_owned(),
+                token,
+            ));
         }
     }
+    let end = match tokens.last() {
+        Some(token) => token.span,
+        None => first,
+    };
+    program.push(Operation::End, end);
 
-    // Reading body
-    // We expect a semicolon to be present at
-    // end of the body
-
-    let mut body = Vec::new();
-    buf_reader
-        .take(512)
-        .read_until(';' as u8, &mut body)
-        .unwrap();
-
-    let body_str = String::from_utf8_lossy(&body).to_string();
-
-    println!("Executing query: \x1b[1;95m{}\x1b[0m", body_str.trim());
-
-    parse(body_str)
+    Ok(program)
 }
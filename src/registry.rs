@@ -0,0 +1,101 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+struct Running {
+    query: String,
+    started: Instant,
+    cancelled: Arc<AtomicBool>,
+}
+
+// Queries being handled by the server, by id
+#[derive(Default)]
+pub struct Registry {
+    next_id: AtomicU64,
+    running: Mutex<BTreeMap<u64, Running>>,
+}
+
+// A running query as listed by `GET /queries`
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryInfo {
+    pub id: u64,
+    pub query: String,
+    pub elapsed: Duration,
+}
+
+// Keeps the query listed until it is dropped
+pub struct Registration<'a> {
+    registry: &'a Registry,
+    pub id: u64,
+    // Set when the query is cancelled, the
+    // program checks it between operations
+    pub cancelled: Arc<AtomicBool>,
+}
+
+impl Registry {
+    pub fn register(&self, query: &str) -> Registration<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        self.running.lock().unwrap().insert(
+            id,
+            Running {
+                query: query.trim().to_owned(),
+                started: Instant::now(),
+                cancelled: Arc::clone(&cancelled),
+            },
+        );
+
+        Registration {
+            registry: self,
+            id,
+            cancelled,
+        }
+    }
+
+    // Oldest first
+    pub fn list(&self) -> Vec<QueryInfo> {
+        let running = self.running.lock().unwrap();
+        running
+            .iter()
+            .map(|(id, query)| QueryInfo {
+                id: *id,
+                query: query.query.clone(),
+                elapsed: query.started.elapsed(),
+            })
+            .collect()
+    }
+
+    // Asks the query to stop, None if it isn't running
+    pub fn cancel(&self, id: u64) -> Option<QueryInfo> {
+        let running = self.running.lock().unwrap();
+        let query = running.get(&id)?;
+        query.cancelled.store(true, Ordering::SeqCst);
+
+        Some(QueryInfo {
+            id,
+            query: query.query.clone(),
+            elapsed: query.started.elapsed(),
+        })
+    }
+
+    // Asks every running query to stop, returns how many there were
+    pub fn cancel_all(&self) -> usize {
+        let running = self.running.lock().unwrap();
+        for query in running.values() {
+            query.cancelled.store(true, Ordering::SeqCst);
+        }
+        running.len()
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.registry.running.lock().unwrap().remove(&self.id);
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
     sync::{mpsc, Arc, Mutex},
     thread,
+    time::{Duration, Instant},
 };
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
@@ -36,6 +37,37 @@ impl ThreadPool {
         let job = Box::new(f);
         self.sender.as_ref().unwrap().send(job).unwrap();
     }
+
+    // Stops taking jobs and waits for the queued ones to run. Workers
+    // still busy after `timeout` are left behind, returns whether
+    // every worker finished in time
+    pub fn shutdown(mut self, timeout: Duration) -> bool {
+        drop(self.sender.take());
+
+        let deadline = Instant::now() + timeout;
+        for i in 0..self.workers.len() {
+            let thread = match self.workers[i].thread.take() {
+                Some(val) => val,
+                None => continue,
+            };
+
+            while !thread.is_finished() {
+                if Instant::now() >= deadline {
+                    // Dropping the handles detaches the workers, so
+                    // dropping the pool doesn't wait for them either
+                    for worker in &mut self.workers[i + 1..] {
+                        worker.thread.take();
+                    }
+                    return false;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            debug!("Shutting down worker {}", self.workers[i].id);
+            thread.join().unwrap();
+        }
+        true
+    }
 }
 
 impl Drop for ThreadPool {
@@ -43,7 +75,7 @@ impl Drop for ThreadPool {
         drop(self.sender.take());
 
         for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+            debug!("Shutting down worker {}", worker.id);
 
             if let Some(thread) = worker.thread.take() {
                 thread.join().unwrap();
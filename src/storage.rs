@@ -0,0 +1,655 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::version::{Versions, PENDING};
+use crate::{intrinsics, Record, RecordId, Table, Value};
+
+// Every change made to the database is described by a mutation,
+// which is what gets written to (and replayed from) the log
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mutation {
+    Set {
+        record_id: RecordId,
+        key: String,
+        value: Value,
+    },
+    Delete {
+        record_id: RecordId,
+    },
+    Unset {
+        record_id: RecordId,
+        key: String,
+    },
+    DropTable {
+        table_name: String,
+    },
+    RenameTable {
+        from: String,
+        to: String,
+    },
+    CreateIndex {
+        table_name: String,
+        key: String,
+        ordered: bool,
+    },
+}
+
+// The data directory holds numbered log segments and snapshots.
+// A snapshot with sequence number n contains every mutation
+// written to segments 0..=n, so only later segments are replayed.
+pub struct Storage {
+    dir: PathBuf,
+    segment: u64,
+    wal: Wal,
+}
+
+pub type Tables = HashMap<String, Table>;
+
+const SNAPSHOT_MAGIC: &[u8] = b"RDBSNAP3";
+// Snapshots written before tables stored their version counter
+const SNAPSHOT_MAGIC_V2: &[u8] = b"RDBSNAP2";
+
+fn segment_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("wal-{:08}.log", seq))
+}
+
+fn snapshot_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("snapshot-{:08}.bin", seq))
+}
+
+// Lists sequence numbers of files named `{prefix}{seq}{suffix}`, sorted
+fn list_files(dir: &Path, prefix: &str, suffix: &str) -> io::Result<Vec<u64>> {
+    let mut seqs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if let Some(seq) = name
+            .strip_prefix(prefix)
+            .and_then(|name| name.strip_suffix(suffix))
+            .and_then(|seq| seq.parse::<u64>().ok())
+        {
+            seqs.push(seq);
+        }
+    }
+    seqs.sort();
+    Ok(seqs)
+}
+
+impl Storage {
+    // Loads the newest valid snapshot and the mutations logged after it
+    pub fn open(dir: &Path) -> io::Result<(Storage, Tables, Vec<Mutation>)> {
+        fs::create_dir_all(dir)?;
+
+        // The segments before the newest snapshot may already be deleted,
+        // so an invalid one stops startup instead of losing what it held
+        let mut tables = HashMap::new();
+        let snapshot_seq = list_files(dir, "snapshot-", ".bin")?.pop();
+        if let Some(seq) = snapshot_seq {
+            let path = snapshot_path(dir, seq);
+            tables = match read_snapshot(&path) {
+                Some(val) => val,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid snapshot {}", path.display()),
+                    ))
+                }
+            };
+        }
+
+        let segments: Vec<_> = list_files(dir, "wal-", ".log")?
+            .into_iter()
+            .filter(|seq| snapshot_seq.is_none_or(|snapshot| *seq > snapshot))
+            .collect();
+
+        let mut mutations = Vec::new();
+        for seq in &segments {
+            let (_, entries) = Wal::open(&segment_path(dir, *seq))?;
+            mutations.extend(entries);
+        }
+
+        let segment = match (segments.last(), snapshot_seq) {
+            (Some(seq), _) => *seq,
+            (None, Some(snapshot)) => snapshot + 1,
+            (None, None) => 0,
+        };
+        let (wal, _) = Wal::open(&segment_path(dir, segment))?;
+
+        let storage = Storage {
+            dir: dir.to_owned(),
+            segment,
+            wal,
+        };
+
+        Ok((storage, tables, mutations))
+    }
+
+    pub fn append(&mut self, mutations: &[Mutation]) -> io::Result<()> {
+        self.wal.append(mutations)
+    }
+
+    // Writes a snapshot of `tables` which must reflect every mutation
+    // appended so far, then starts a new segment and deletes the files
+    // the snapshot makes obsolete
+    pub fn snapshot(&mut self, tables: &[(&str, &Table)]) -> io::Result<()> {
+        let seq = self.segment;
+
+        let path = snapshot_path(&self.dir, seq);
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&encode_snapshot(tables))?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &path)?;
+        sync_dir(&self.dir)?;
+
+        let (wal, _) = Wal::open(&segment_path(&self.dir, seq + 1))?;
+        self.wal = wal;
+        self.segment = seq + 1;
+
+        for old in list_files(&self.dir, "wal-", ".log")? {
+            if old <= seq {
+                fs::remove_file(segment_path(&self.dir, old))?;
+            }
+        }
+        for old in list_files(&self.dir, "snapshot-", ".bin")? {
+            if old < seq {
+                fs::remove_file(snapshot_path(&self.dir, old))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    // Directories can only be synced on unix
+    if cfg!(unix) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// Snapshots are laid out as
+// [magic][checksum: u32][payload]
+// with each table written as [name][indexes][version][records]
+// and each record as [row][version][fields]
+// Only the indexed fields are stored, indexes are rebuilt on load
+fn encode_snapshot(tables: &[(&str, &Table)]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&(tables.len() as u32).to_le_bytes());
+    for (table_name, table) in tables {
+        encode_str(&mut payload, table_name);
+        payload.extend_from_slice(&(table.indexes.len() as u32).to_le_bytes());
+        for (key, index) in &table.indexes {
+            encode_str(&mut payload, key);
+            payload.push(index.is_ordered() as u8);
+        }
+        payload.extend_from_slice(&table.version.to_le_bytes());
+        // Snapshots are taken with every table locked, when no
+        // version is pending the newest ones are the latest commit
+        let records: Vec<_> = table.rows(PENDING).collect();
+        payload.extend_from_slice(&(records.len() as u64).to_le_bytes());
+        for (row, record) in records {
+            payload.extend_from_slice(&row.to_le_bytes());
+            payload.extend_from_slice(&record.version.to_le_bytes());
+            payload.extend_from_slice(&(record.fields.len() as u32).to_le_bytes());
+            for (key, value) in &record.fields {
+                encode_str(&mut payload, key);
+                encode_value(&mut payload, value);
+            }
+        }
+    }
+
+    let mut buf = SNAPSHOT_MAGIC.to_vec();
+    buf.extend_from_slice(&checksum(&payload).to_le_bytes());
+    buf.extend_from_slice(&payload);
+    buf
+}
+
+fn read_snapshot(path: &Path) -> Option<Tables> {
+    let bytes = fs::read(path).ok()?;
+    let mut decoder = Decoder { bytes: &bytes };
+
+    let counted = match decoder.take(SNAPSHOT_MAGIC.len())? {
+        SNAPSHOT_MAGIC => true,
+        SNAPSHOT_MAGIC_V2 => false,
+        _ => return None,
+    };
+    let sum = decoder.u32()?;
+    if checksum(decoder.bytes) != sum {
+        return None;
+    }
+
+    let mut tables = HashMap::new();
+    for _ in 0..decoder.u32()? {
+        let table_name = decoder.string()?;
+        let mut keys = Vec::new();
+        for _ in 0..decoder.u32()? {
+            let key = decoder.string()?;
+            let ordered = decoder.u8()? != 0;
+            keys.push((key, ordered));
+        }
+
+        let mut table = Table::default();
+        if counted {
+            table.version = decoder.u64()?;
+        }
+        for _ in 0..decoder.u64()? {
+            let row = decoder.u64()?;
+            let version = decoder.u64()?;
+            // Without a counter the newest record's version is the best guess
+            table.version = table.version.max(version);
+            let mut fields = HashMap::new();
+            for _ in 0..decoder.u32()? {
+                let key = decoder.string()?;
+                let value = decoder.value()?;
+                fields.insert(key, value);
+            }
+            table
+                .records
+                .insert(row, Versions::new(Record { fields, version }));
+        }
+        for (key, ordered) in keys {
+            intrinsics::create_index(&mut table, key, ordered);
+        }
+        tables.insert(table_name, table);
+    }
+
+    if !decoder.bytes.is_empty() {
+        return None;
+    }
+    Some(tables)
+}
+
+// Log entries are framed as
+// [payload length: u32][checksum: u32][payload]
+// Each entry holds the mutations of one commit, so a crash
+// never leaves part of a transaction behind
+const ENTRY_HEADER_LEN: usize = 8;
+
+// What the log needs from the file it appends to
+pub trait LogFile: Write + Seek {
+    fn set_len(&self, len: u64) -> io::Result<()>;
+    fn sync_data(&self) -> io::Result<()>;
+}
+
+impl LogFile for File {
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+pub struct Wal<F = File> {
+    file: F,
+    // Set when a failed append couldn't be undone, appending after
+    // its bytes would lose the entry on the next replay
+    poisoned: bool,
+}
+
+impl Wal {
+    // Opens the log at `path`, creating it if needed, and returns
+    // every complete entry found in it. A torn or corrupt tail left
+    // behind by a crash is truncated away.
+    pub fn open(path: &Path) -> io::Result<(Wal, Vec<Mutation>)> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let created = !path.exists();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut mutations = Vec::new();
+        let mut pos = 0;
+        while let Some((entry, len)) = read_entry(&bytes[pos..]) {
+            mutations.extend(entry);
+            pos += len;
+        }
+
+        if pos != bytes.len() {
+            warn!("discarding {} bytes of incomplete log", bytes.len() - pos);
+            file.set_len(pos as u64)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(pos as u64))?;
+
+        // A new segment only survives a crash once its directory is synced
+        if created {
+            if let Some(dir) = path.parent() {
+                sync_dir(dir)?;
+            }
+        }
+
+        Ok((Wal::new(file), mutations))
+    }
+}
+
+impl<F: LogFile> Wal<F> {
+    // Appends at the current position of `file`
+    pub fn new(file: F) -> Wal<F> {
+        Wal {
+            file,
+            poisoned: false,
+        }
+    }
+
+    // Appends the mutations as one entry and waits until they reach the disk
+    pub fn append(&mut self, mutations: &[Mutation]) -> io::Result<()> {
+        if mutations.is_empty() {
+            return Ok(());
+        }
+
+        let mut payload = vec![ENTRY_BATCH];
+        payload.extend_from_slice(&(mutations.len() as u32).to_le_bytes());
+        for mutation in mutations {
+            encode_mutation(&mut payload, mutation);
+        }
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&checksum(&payload).to_le_bytes());
+        buf.extend_from_slice(&payload);
+
+        if self.poisoned {
+            return Err(io::Error::other(
+                "log is unusable after a failed write, take a snapshot",
+            ));
+        }
+
+        // A failed write is rolled back in memory, so its bytes are
+        // removed even if they all made it, or replay would revive them
+        let offset = self.file.stream_position()?;
+        let result = self
+            .file
+            .write_all(&buf)
+            .and_then(|_| self.file.sync_data());
+        if result.is_err() {
+            let undone = self
+                .file
+                .set_len(offset)
+                .and_then(|_| self.file.seek(SeekFrom::Start(offset)))
+                .and_then(|_| self.file.sync_data());
+            if undone.is_err() {
+                self.poisoned = true;
+            }
+        }
+        result
+    }
+}
+
+fn read_entry(bytes: &[u8]) -> Option<(Vec<Mutation>, usize)> {
+    if bytes.len() < ENTRY_HEADER_LEN {
+        return None;
+    }
+
+    let len = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+    let sum = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+
+    let payload = bytes.get(ENTRY_HEADER_LEN..ENTRY_HEADER_LEN + len)?;
+    if checksum(payload) != sum {
+        return None;
+    }
+
+    let mut decoder = Decoder { bytes: payload };
+    if decoder.u8()? != ENTRY_BATCH {
+        return None;
+    }
+    let count = decoder.u32()?;
+    let mutations = (0..count)
+        .map(|_| decoder.mutation())
+        .collect::<Option<Vec<_>>>()?;
+    if !decoder.bytes.is_empty() {
+        return None;
+    }
+
+    Some((mutations, ENTRY_HEADER_LEN + len))
+}
+
+// CRC-32 (IEEE)
+pub fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+// Encoding
+
+const MUTATION_SET: u8 = 0;
+const MUTATION_DELETE: u8 = 1;
+const MUTATION_UNSET: u8 = 2;
+const MUTATION_DROP_TABLE: u8 = 3;
+const MUTATION_RENAME_TABLE: u8 = 4;
+const MUTATION_CREATE_INDEX: u8 = 5;
+// Starts every log entry, followed by the number of mutations
+const ENTRY_BATCH: u8 = 0xff;
+
+const VALUE_ID: u8 = 0;
+const VALUE_INT: u8 = 1;
+const VALUE_FLOAT: u8 = 2;
+const VALUE_STRING: u8 = 3;
+const VALUE_ARRAY: u8 = 4;
+const VALUE_LINK: u8 = 5;
+const VALUE_NULL: u8 = 6;
+const VALUE_BOOL: u8 = 7;
+
+fn encode_str(buf: &mut Vec<u8>, str: &str) {
+    buf.extend_from_slice(&(str.len() as u32).to_le_bytes());
+    buf.extend_from_slice(str.as_bytes());
+}
+
+fn encode_record_id(buf: &mut Vec<u8>, record_id: &RecordId) {
+    encode_str(buf, &record_id.table_name);
+    buf.extend_from_slice(&record_id.row.to_le_bytes());
+}
+
+pub fn encode_value(buf: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Id(record_id) => {
+            buf.push(VALUE_ID);
+            encode_record_id(buf, record_id);
+        }
+        Value::Null => buf.push(VALUE_NULL),
+        Value::Bool(val) => {
+            buf.push(VALUE_BOOL);
+            buf.push(*val as u8);
+        }
+        Value::Int(val) => {
+            buf.push(VALUE_INT);
+            buf.extend_from_slice(&val.to_le_bytes());
+        }
+        Value::Float(val) => {
+            buf.push(VALUE_FLOAT);
+            buf.extend_from_slice(&val.to_bits().to_le_bytes());
+        }
+        Value::String(val) => {
+            buf.push(VALUE_STRING);
+            encode_str(buf, val);
+        }
+        Value::Array(values) => {
+            buf.push(VALUE_ARRAY);
+            buf.extend_from_slice(&(values.len() as u32).to_le_bytes());
+            for value in values {
+                encode_value(buf, value);
+            }
+        }
+        Value::RecordLink(record_id) => {
+            buf.push(VALUE_LINK);
+            encode_record_id(buf, record_id);
+        }
+    }
+}
+
+fn encode_mutation(buf: &mut Vec<u8>, mutation: &Mutation) {
+    match mutation {
+        Mutation::Set {
+            record_id,
+            key,
+            value,
+        } => {
+            buf.push(MUTATION_SET);
+            encode_record_id(buf, record_id);
+            encode_str(buf, key);
+            encode_value(buf, value);
+        }
+        Mutation::Delete { record_id } => {
+            buf.push(MUTATION_DELETE);
+            encode_record_id(buf, record_id);
+        }
+        Mutation::Unset { record_id, key } => {
+            buf.push(MUTATION_UNSET);
+            encode_record_id(buf, record_id);
+            encode_str(buf, key);
+        }
+        Mutation::DropTable { table_name } => {
+            buf.push(MUTATION_DROP_TABLE);
+            encode_str(buf, table_name);
+        }
+        Mutation::RenameTable { from, to } => {
+            buf.push(MUTATION_RENAME_TABLE);
+            encode_str(buf, from);
+            encode_str(buf, to);
+        }
+        Mutation::CreateIndex {
+            table_name,
+            key,
+            ordered,
+        } => {
+            buf.push(MUTATION_CREATE_INDEX);
+            encode_str(buf, table_name);
+            encode_str(buf, key);
+            buf.push(*ordered as u8);
+        }
+    }
+}
+
+// Decoding
+
+pub struct Decoder<'a> {
+    pub bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < n {
+            return None;
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Some(head)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+
+    pub fn record_id(&mut self) -> Option<RecordId> {
+        let table_name = self.string()?;
+        let row = self.u64()?;
+        Some(RecordId { table_name, row })
+    }
+
+    pub fn value(&mut self) -> Option<Value> {
+        match self.u8()? {
+            VALUE_ID => Some(Value::Id(self.record_id()?)),
+            VALUE_NULL => Some(Value::Null),
+            VALUE_BOOL => match self.u8()? {
+                0 => Some(Value::Bool(false)),
+                1 => Some(Value::Bool(true)),
+                _ => None,
+            },
+            VALUE_INT => Some(Value::Int(self.u64()? as i64)),
+            VALUE_FLOAT => Some(Value::Float(f64::from_bits(self.u64()?))),
+            VALUE_STRING => Some(Value::String(self.string()?)),
+            VALUE_ARRAY => {
+                let len = self.u32()?;
+                let mut values = Vec::new();
+                for _ in 0..len {
+                    values.push(self.value()?);
+                }
+                Some(Value::Array(values))
+            }
+            VALUE_LINK => Some(Value::RecordLink(self.record_id()?)),
+            _ => None,
+        }
+    }
+
+    fn mutation(&mut self) -> Option<Mutation> {
+        match self.u8()? {
+            MUTATION_SET => {
+                let record_id = self.record_id()?;
+                let key = self.string()?;
+                let value = self.value()?;
+                Some(Mutation::Set {
+                    record_id,
+                    key,
+                    value,
+                })
+            }
+            MUTATION_DELETE => {
+                let record_id = self.record_id()?;
+                Some(Mutation::Delete { record_id })
+            }
+            MUTATION_UNSET => {
+                let record_id = self.record_id()?;
+                let key = self.string()?;
+                Some(Mutation::Unset { record_id, key })
+            }
+            MUTATION_DROP_TABLE => {
+                let table_name = self.string()?;
+                Some(Mutation::DropTable { table_name })
+            }
+            MUTATION_RENAME_TABLE => {
+                let from = self.string()?;
+                let to = self.string()?;
+                Some(Mutation::RenameTable { from, to })
+            }
+            MUTATION_CREATE_INDEX => {
+                let table_name = self.string()?;
+                let key = self.string()?;
+                let ordered = self.u8()? != 0;
+                Some(Mutation::CreateIndex {
+                    table_name,
+                    key,
+                    ordered,
+                })
+            }
+            _ => None,
+        }
+    }
+}
@@ -1,2 +1,1773 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::http;
+use crate::intrinsics::{self, Predicate};
+use crate::json::JsonWriter;
+use crate::query::{parse, Span};
+use crate::registry::Registry;
+use crate::server::ThreadPool;
+use crate::storage::{self, LogFile, Mutation, Wal};
+use crate::version::PENDING;
+use crate::{
+    error_to_json, execute_program, manage_queries, request_limits, results_to_json, Budget,
+    Config, Database, DatabaseRef, Limits, LogLevel, OutputItem, QueryResult, RecordId, ResultItem,
+    ServerConfig, Table, Value,
+};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("real_db_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn open(dir: &Path) -> DatabaseRef {
+    let config = Config {
+        data_dir: Some(dir.to_owned()),
+    };
+    Arc::new(Database::open(config).unwrap())
+}
+
+fn query<'a>(database: &'a DatabaseRef, contents: &str) -> Result<QueryResult<'a>, Error> {
+    execute_program(database, parse(contents)?, Budget::new(Limits::default()))
+}
+
+fn memory() -> DatabaseRef {
+    Arc::new(Database::open(Config::default()).unwrap())
+}
+
+// Runs `f` with the table read locked
+fn table<R>(database: &DatabaseRef, name: &str, f: impl FnOnce(&Table) -> R) -> R {
+    let tables = database.tables.read().unwrap();
+    let table = tables[name].read();
+    f(&table)
+}
+
+// Fields of the newest version of the record
+fn fields(table: &Table, row: u64) -> &HashMap<String, Value> {
+    &table.get(row, PENDING).unwrap().fields
+}
+
 #[test]
-fn search_benchmark() {}
+fn readers_run_concurrently() {
+    assert!(parse("@users:0 Select_All Tables").unwrap().is_read_only());
+    assert!(!parse("@users:0 \"a\" 1 Set").unwrap().is_read_only());
+
+    let database = memory();
+    query(&database, "@users:1 \"n\" 1 Set").unwrap();
+
+    // A read-only query finishes while a writer holds the table
+    // and another reader is in the middle of reading it
+    let tables = database.tables.read().unwrap();
+    let _writer = tables["users"].writer.lock().unwrap();
+    let _reading = tables["users"].read();
+
+    let (sender, receiver) = mpsc::channel();
+    let reader = {
+        let database = Arc::clone(&database);
+        thread::spawn(move || {
+            let result = query(&database, "@users:_ \"n\" 1 \"==\" Filter");
+            sender.send(result.unwrap().items.len()).unwrap();
+        })
+    };
+    assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(1));
+    reader.join().unwrap();
+}
+
+#[test]
+fn tables_are_locked_separately() {
+    let database = memory();
+    query(&database, "@a:1 \"n\" 1 Set @b:1 \"n\" 1 Set").unwrap();
+
+    // A write to `a` goes through while `b` is locked
+    let tables = database.tables.read().unwrap();
+    let b = tables["b"].writer.lock().unwrap();
+
+    let (sender, receiver) = mpsc::channel();
+    let writer = {
+        let database = Arc::clone(&database);
+        thread::spawn(move || {
+            let result = query(&database, "@a:1 \"n\" 2 Set");
+            sender.send(result.is_ok()).unwrap();
+        })
+    };
+    assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(true));
+
+    drop(b);
+    drop(tables);
+    writer.join().unwrap();
+
+    // Programs naming the same tables in opposite orders
+    // lock them in the same order and can't deadlock
+    let threads: Vec<_> = (0..4)
+        .map(|i| {
+            let database = Arc::clone(&database);
+            thread::spawn(move || {
+                let contents = if i % 2 == 0 {
+                    "@a:1 \"n\" 2 Set Drop @b:1 \"n\" 2 Set"
+                } else {
+                    "@b:1 \"n\" 3 Set Drop @a:1 \"n\" 2 Set"
+                };
+                for _ in 0..200 {
+                    query(&database, contents).unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let record = database.get(&RecordId::new("a", 1)).unwrap();
+    assert_eq!(record.get("n"), Some(&Value::Int(2)));
+}
+
+// Writing to a new table only locks the catalog while the table is
+// added, not while the writer waits for its other tables
+#[test]
+fn new_tables_dont_block_readers() {
+    let database = memory();
+    query(&database, "@users:1 \"n\" 1 Set").unwrap();
+
+    let users = Arc::clone(&database.tables.read().unwrap()["users"]);
+    let held = users.writer.lock().unwrap();
+
+    let writer = {
+        let database = Arc::clone(&database);
+        thread::spawn(move || {
+            query(&database, "@logs:1 \"n\" 1 Set Drop @users:1 \"n\" 2 Set").map(|_| ())
+        })
+    };
+    let start = Instant::now();
+    while !database.tables.read().unwrap().contains_key("logs") {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::yield_now();
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let reader = {
+        let database = Arc::clone(&database);
+        thread::spawn(move || {
+            let result = query(&database, "Tables");
+            sender.send(result.unwrap().items.len()).unwrap();
+        })
+    };
+    assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(3));
+    reader.join().unwrap();
+
+    drop(held);
+    writer.join().unwrap().unwrap();
+    let record = database.get(&RecordId::new("logs", 1)).unwrap();
+    assert_eq!(record.get("n"), Some(&Value::Int(1)));
+}
+
+#[test]
+fn readers_see_a_snapshot() {
+    let database = memory();
+    query(
+        &database,
+        "@users:1 \"n\" 1 Set @users:2 \"n\" 1 Set \"users\" \"n\" Create_Index",
+    )
+    .unwrap();
+    let versions = |row| {
+        table(&database, "users", |table| {
+            table.records[&row].records().count()
+        })
+    };
+
+    // A read-only query doesn't wait for a writer in the
+    // middle of its program and doesn't see its writes
+    {
+        let tables = database.tables.read().unwrap();
+        let users = &tables["users"];
+        let _writer = users.writer.lock().unwrap();
+        intrinsics::set(
+            &mut users.write(),
+            &RecordId::new("users", 1),
+            "n".to_owned(),
+            Value::Int(2),
+        );
+
+        let (sender, receiver) = mpsc::channel();
+        let reader = {
+            let database = Arc::clone(&database);
+            thread::spawn(move || {
+                let result = query(&database, "@users:_ \"n\" 1 \"==\" Filter");
+                sender.send(result.unwrap().items.len()).unwrap();
+            })
+        };
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(2));
+        reader.join().unwrap();
+
+        intrinsics::discard(&mut users.write(), 1);
+    }
+
+    // Versions a reader can see are kept until it finishes
+    let reader = database.begin_read(&database.tables.read().unwrap());
+    query(&database, "@users:1 \"n\" 2 Set @users:2 Delete").unwrap();
+    query(&database, "@users:1 \"n\" 3 Set").unwrap();
+    assert_eq!(versions(1), 3);
+
+    table(&database, "users", |table| {
+        let filter = |ts, value| {
+            let mut items = Vec::new();
+            let key = "n".to_owned();
+            intrinsics::filter(
+                table,
+                ts,
+                &mut items,
+                key,
+                Value::Int(value),
+                Predicate::Equal,
+            );
+            items.len()
+        };
+        assert_eq!(filter(reader.ts, 1), 2);
+        assert_eq!(filter(reader.ts, 3), 0);
+        assert_eq!(filter(PENDING, 1), 0);
+        assert_eq!(filter(PENDING, 3), 1);
+        assert!(table.get(2, PENDING).is_none());
+    });
+    let record = database.get(&RecordId::new("users", 1)).unwrap();
+    assert_eq!(record.get("n"), Some(&Value::Int(3)));
+
+    // They go as soon as no reader needs them anymore
+    drop(reader);
+    assert_eq!(versions(1), 1);
+    table(&database, "users", |table| {
+        assert!(!table.records.contains_key(&2));
+        assert!(table.stale.is_empty());
+        assert_eq!(
+            table.indexes["n"].lookup(Predicate::Equal, &Value::Int(1)),
+            Some(Vec::new())
+        );
+    });
+}
+
+// Output of a read-only query is rendered at its snapshot,
+// even when other queries commit before it is built
+#[test]
+fn output_is_rendered_at_the_snapshot() {
+    let database = memory();
+    query(
+        &database,
+        "@users:1 \"n\" 1 Set \"friend\" @users:2 Link Set Drop
+         @users:2 \"n\" 1 Set Drop",
+    )
+    .unwrap();
+
+    let result = query(&database, "1 Expand @users:_ \"n\" 1 \"==\" Filter").unwrap();
+    query(&database, "@users:1 \"n\" 2 Set Drop @users:2 Delete").unwrap();
+    database.snapshot().unwrap();
+
+    let json = results_to_json(&database, result);
+    assert_eq!(json.matches("\"n\":1").count(), 3);
+    assert!(!json.contains("\"n\":2"));
+
+    // The old versions go once the output is built
+    assert_eq!(table(&database, "users", |table| table.records.len()), 1);
+
+    // Records of a table dropped after the query are still rendered
+    let result = query(&database, "@users:1 Select").unwrap();
+    query(&database, "\"users\" Drop_Table").unwrap();
+    assert!(results_to_json(&database, result).contains("\"n\":2"));
+}
+
+#[test]
+fn log_is_replayed_on_open() {
+    let dir = temp_dir("log_replay");
+
+    {
+        let database = open(&dir);
+        query(
+            &database,
+            "Range 3 do @users:1 \"n\" it Set Drop End @users:7 \"age\" 22 Set",
+        )
+        .unwrap();
+    }
+
+    let database = open(&dir);
+    table(&database, "users", |table| {
+        let records = &table.records;
+        assert_eq!(records.len(), 2);
+        assert_eq!(fields(table, 1)["n"], Value::Int(1));
+        assert_eq!(fields(table, 7)["age"], Value::Int(22));
+    });
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn torn_log_tail_is_discarded() {
+    let dir = temp_dir("torn_log");
+
+    {
+        let database = open(&dir);
+        query(&database, "@users:1 \"age\" 22 Set").unwrap();
+    }
+
+    let path = dir.join("wal-00000000.log");
+    let mut bytes = std::fs::read(&path).unwrap();
+    let len = bytes.len();
+    bytes.extend_from_within(..len - 3);
+    std::fs::write(&path, bytes).unwrap();
+
+    let database = open(&dir);
+    assert_eq!(table(&database, "users", |table| table.records.len()), 1);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), len as u64);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn torn_transaction_is_discarded() {
+    let dir = temp_dir("torn_transaction");
+    let path = dir.join("wal-00000000.log");
+
+    let committed = {
+        let database = open(&dir);
+        query(&database, "@users:1 \"n\" 1 Set").unwrap();
+        let committed = std::fs::metadata(&path).unwrap().len();
+        query(
+            &database,
+            "@users:2 \"n\" 2 Set Drop @users:3 \"n\" 3 Set Drop @users:1 \"n\" 4 Set",
+        )
+        .unwrap();
+        committed
+    };
+
+    // Cut inside the last write of the second query
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::write(&path, &bytes[..bytes.len() - 3]).unwrap();
+
+    let database = open(&dir);
+    table(&database, "users", |table| {
+        assert_eq!(table.records.len(), 1);
+        assert_eq!(fields(table, 1)["n"], Value::Int(1));
+    });
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), committed);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// A log file whose writes, syncs and truncations can be made to fail
+#[derive(Clone, Default)]
+struct FaultyFile(Rc<RefCell<Faults>>);
+
+#[derive(Default)]
+struct Faults {
+    bytes: Vec<u8>,
+    pos: usize,
+    // Bytes accepted before writes start failing
+    accept: Option<usize>,
+    // Syncs that fail before they succeed again
+    failed_syncs: usize,
+    fail_truncate: bool,
+}
+
+impl Write for FaultyFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut faults = self.0.borrow_mut();
+        let len = match faults.accept {
+            Some(0) => return Err(io::Error::other("disk full")),
+            Some(accept) => buf.len().min(accept),
+            None => buf.len(),
+        };
+        faults.accept = faults.accept.map(|accept| accept - len);
+
+        let pos = faults.pos;
+        if faults.bytes.len() < pos + len {
+            faults.bytes.resize(pos + len, 0);
+        }
+        faults.bytes[pos..pos + len].copy_from_slice(&buf[..len]);
+        faults.pos += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for FaultyFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let mut faults = self.0.borrow_mut();
+        faults.pos = match pos {
+            SeekFrom::Start(offset) => offset as usize,
+            SeekFrom::End(offset) => (faults.bytes.len() as i64 + offset) as usize,
+            SeekFrom::Current(offset) => (faults.pos as i64 + offset) as usize,
+        };
+        Ok(faults.pos as u64)
+    }
+}
+
+impl LogFile for FaultyFile {
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        let mut faults = self.0.borrow_mut();
+        if faults.fail_truncate {
+            return Err(io::Error::other("truncate failed"));
+        }
+        faults.bytes.truncate(len as usize);
+        Ok(())
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        let mut faults = self.0.borrow_mut();
+        if faults.failed_syncs > 0 {
+            faults.failed_syncs -= 1;
+            return Err(io::Error::other("sync failed"));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn failed_log_appends() {
+    let file = FaultyFile::default();
+    let mut wal = Wal::new(file.clone());
+    let delete = |row| Mutation::Delete {
+        record_id: RecordId::new("users", row),
+    };
+    let len = || file.0.borrow().bytes.len();
+
+    wal.append(&[delete(1)]).unwrap();
+    let entry = len();
+
+    // A torn write and a write that didn't sync are both removed
+    file.0.borrow_mut().accept = Some(5);
+    assert!(wal.append(&[delete(2)]).is_err());
+    assert_eq!(len(), entry);
+
+    file.0.borrow_mut().accept = None;
+    file.0.borrow_mut().failed_syncs = 1;
+    assert!(wal.append(&[delete(2)]).is_err());
+    assert_eq!(len(), entry);
+
+    wal.append(&[delete(3)]).unwrap();
+    assert_eq!(len(), 2 * entry);
+
+    // Bytes that can't be removed stop every later append
+    file.0.borrow_mut().accept = Some(5);
+    file.0.borrow_mut().fail_truncate = true;
+    assert!(wal.append(&[delete(4)]).is_err());
+    assert_eq!(len(), 2 * entry + 5);
+
+    file.0.borrow_mut().accept = None;
+    file.0.borrow_mut().fail_truncate = false;
+    assert!(wal.append(&[delete(5)]).is_err());
+    assert_eq!(len(), 2 * entry + 5);
+}
+
+#[test]
+fn snapshot_truncates_log() {
+    let dir = temp_dir("snapshot");
+
+    {
+        let database = open(&dir);
+        query(
+            &database,
+            "@users:1 \"age\" 22 Set Snapshot @users:2 \"age\" 23 Set",
+        )
+        .unwrap();
+    }
+
+    assert!(dir.join("snapshot-00000000.bin").exists());
+    assert!(!dir.join("wal-00000000.log").exists());
+
+    {
+        let database = open(&dir);
+        table(&database, "users", |table| {
+            let records = &table.records;
+            assert_eq!(records.len(), 2);
+            assert_eq!(fields(table, 1)["age"], Value::Int(22));
+        });
+
+        database.snapshot().unwrap();
+    }
+
+    assert!(!dir.join("snapshot-00000000.bin").exists());
+
+    {
+        let database = open(&dir);
+        assert_eq!(table(&database, "users", |table| table.records.len()), 2);
+    }
+
+    // A corrupt snapshot stops startup rather than losing the tables
+    let path = dir.join("snapshot-00000001.bin");
+    let mut bytes = std::fs::read(&path).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 1;
+    std::fs::write(&path, bytes).unwrap();
+    let config = Config {
+        data_dir: Some(dir.clone()),
+    };
+    assert!(Database::open(config).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn delete_and_unset() {
+    let database = memory();
+
+    query(
+        &database,
+        "@users:1 \"age\" 22 Set \"name\" \"x\" Set Drop @users:2 \"age\" 23 Set Drop",
+    )
+    .unwrap();
+
+    query(&database, "@users:1 \"Name\" Unset Drop @users:2 Delete").unwrap();
+    table(&database, "users", |table| {
+        let records = &table.records;
+        assert_eq!(records.len(), 1);
+        assert!(!fields(table, 1).contains_key("name"));
+        assert_eq!(fields(table, 1)["age"], Value::Int(22));
+    });
+
+    assert!(query(&database, "@users:2 Delete").is_err());
+    assert!(query(&database, "@users:1 \"id\" Unset").is_err());
+}
+
+#[test]
+fn failed_programs_are_rolled_back() {
+    let dir = temp_dir("rollback");
+
+    {
+        let database = open(&dir);
+        query(
+            &database,
+            "@users:1 \"age\" 22 Set Drop @users:2 \"age\" 23 Set Drop \"users\" \"age\" Create_Index",
+        )
+        .unwrap();
+
+        let err = query(
+            &database,
+            "@users:1 \"age\" 30 Set \"name\" \"x\" Set Drop @users:2 Delete @users:3 \"age\" 22 Set
+             @orders:1 \"total\" 5 Set Drop \"x\" Delete",
+        )
+        .unwrap_err();
+        assert_eq!(err.position(), Some((2, 47)));
+
+        let err = query(
+            &database,
+            "\"users\" \"n\" Create_Ordered_Index \"users\" \"people\" Rename_Table
+             \"people\" Drop_Table \"missing\" Drop_Table",
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::TableNotFound { .. }));
+
+        let result = query(&database, "Tables @users:_ \"age\" 22 \"==\" Filter").unwrap();
+        assert_eq!(
+            result.items,
+            vec![
+                ResultItem::Table {
+                    name: "0".to_owned(),
+                    rows: 0
+                },
+                ResultItem::Table {
+                    name: "users".to_owned(),
+                    rows: 2
+                },
+                ResultItem::Record(RecordId::new("users", 1)),
+            ]
+        );
+        table(&database, "users", |table| {
+            assert!(!fields(table, 1).contains_key("name"));
+            assert!(!table.indexes.contains_key("n"));
+        });
+    }
+
+    // Nothing of the failed programs reached the log
+    let database = open(&dir);
+    let result = query(&database, "Tables").unwrap();
+    assert_eq!(result.items.len(), 2);
+    assert_eq!(
+        database
+            .get(&RecordId::new("users", 1))
+            .unwrap()
+            .fields()
+            .len(),
+        2
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn begin_commit_rollback() {
+    let database = memory();
+    let n = |row: u64| {
+        database
+            .get(&RecordId::new("users", row))
+            .and_then(|record| record.get("n").cloned())
+    };
+
+    let err = query(
+        &database,
+        "Begin @users:1 \"n\" 1 Set Commit
+         Begin @users:1 \"n\" 2 Set @users:2 \"n\" 2 Set Rollback
+         Begin @users:3 \"n\" 3 Set Commit
+         @users:4 \"n\" 4 Set \"x\" Delete",
+    )
+    .unwrap_err();
+    assert!(matches!(err, Error::TypeError { .. }));
+    assert_eq!(n(1), Some(Value::Int(1)));
+    assert_eq!(n(2), None);
+    assert_eq!(n(3), Some(Value::Int(3)));
+    assert_eq!(n(4), None);
+
+    query(
+        &database,
+        "Range 3 do Begin @users:5 \"n\" it Set Drop Commit End",
+    )
+    .unwrap();
+    assert_eq!(n(5), Some(Value::Int(1)));
+
+    // Writes can't be pulled into a transaction after the fact
+    let err = query(
+        &database,
+        "@users:6 \"n\" 6 Set\nBegin @users:7 \"n\" 7 Set Commit",
+    )
+    .unwrap_err();
+    assert_eq!(err.position(), Some((2, 1)));
+    assert_eq!(n(6), None);
+
+    let err = query(&database, "1\nBegin @users:7 \"n\" 7 Set").unwrap_err();
+    assert_eq!(err.position(), Some((2, 1)));
+    assert_eq!(n(7), None);
+
+    assert!(query(&database, "Commit").is_err());
+    assert!(query(&database, "Rollback").is_err());
+    assert!(query(&database, "Begin Begin Commit").is_err());
+}
+
+#[test]
+fn record_versions() {
+    let dir = temp_dir("versions");
+    let version = |database: &DatabaseRef, row: u64| {
+        database
+            .get(&RecordId::new("users", row))
+            .map(|record| record.version())
+    };
+
+    {
+        let database = open(&dir);
+        query(
+            &database,
+            "@users:1 \"n\" 1 Set \"m\" 1 Set \"m\" Unset Drop @users:2 \"n\" 2 0 Set_If_Version",
+        )
+        .unwrap();
+        assert_eq!(version(&database, 1), Some(3));
+        assert_eq!(version(&database, 2), Some(4));
+
+        let result = query(&database, "@users:1 Select").unwrap();
+        assert!(results_to_json(&database, result).contains("\"_version\":3"));
+
+        query(&database, "@users:1 \"n\" 2 3 Set_If_Version").unwrap();
+
+        // A stale version fails the whole query
+        let err = query(
+            &database,
+            "@users:2 \"n\" 3 Set Drop @users:1 \"n\" 3 3 Set_If_Version",
+        )
+        .unwrap_err();
+        assert_eq!(err.code(), "conflict");
+        assert_eq!(err.status(), 409);
+        assert_eq!(err.position(), Some((1, 42)));
+        assert_eq!(version(&database, 1), Some(5));
+        assert_eq!(version(&database, 2), Some(4));
+
+        // A record set again after a delete doesn't reuse a version,
+        // the rolled back write above gave its version back
+        query(
+            &database,
+            "@users:2 Delete @users:2 \"n\" 1 0 Set_If_Version",
+        )
+        .unwrap();
+        assert_eq!(version(&database, 2), Some(6));
+        assert!(query(&database, "@users:2 \"n\" 2 4 Set_If_Version").is_err());
+
+        assert!(query(&database, "@users:3 \"n\" 3 1 Set_If_Version").is_err());
+        assert!(query(&database, "@users:1 \"n\" 3 \"4\" Set_If_Version").is_err());
+        assert!(query(&database, "@users:1 \"_Version\" 1 Set").is_err());
+        assert!(query(&database, "@users:1 \"_version\" Unset").is_err());
+    }
+
+    // Versions survive replaying the log and snapshots
+    {
+        let database = open(&dir);
+        assert_eq!(version(&database, 1), Some(5));
+        assert_eq!(version(&database, 2), Some(6));
+        query(&database, "@users:2 Delete").unwrap();
+        database.snapshot().unwrap();
+    }
+    let database = open(&dir);
+    assert_eq!(version(&database, 1), Some(5));
+    query(&database, "@users:2 \"n\" 1 Set").unwrap();
+    assert_eq!(version(&database, 2), Some(7));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// Snapshots from before tables kept their version counter
+// continue from the newest record's version
+#[test]
+fn version_2_snapshot() {
+    let dir = temp_dir("snapshot_v2");
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let str = |buf: &mut Vec<u8>, str: &str| {
+        buf.extend_from_slice(&(str.len() as u32).to_le_bytes());
+        buf.extend_from_slice(str.as_bytes());
+    };
+    let mut payload = 1u32.to_le_bytes().to_vec();
+    str(&mut payload, "users");
+    payload.extend_from_slice(&0u32.to_le_bytes());
+    payload.extend_from_slice(&1u64.to_le_bytes());
+    payload.extend_from_slice(&1u64.to_le_bytes());
+    payload.extend_from_slice(&4u64.to_le_bytes());
+    payload.extend_from_slice(&1u32.to_le_bytes());
+    str(&mut payload, "n");
+    storage::encode_value(&mut payload, &Value::Int(1));
+
+    let mut bytes = b"RDBSNAP2".to_vec();
+    bytes.extend_from_slice(&storage::checksum(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    std::fs::write(dir.join("snapshot-00000000.bin"), bytes).unwrap();
+
+    let database = open(&dir);
+    let id = RecordId::new("users", 1);
+    assert_eq!(database.get(&id).unwrap().get("n"), Some(&Value::Int(1)));
+    query(&database, "@users:2 \"n\" 2 Set").unwrap();
+    assert_eq!(
+        database.get(&RecordId::new("users", 2)).unwrap().version(),
+        5
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn query_limits() {
+    let database = memory();
+    let limits = |max_instructions: u64, timeout: u64| Limits {
+        max_instructions,
+        timeout: Duration::from_millis(timeout),
+        max_expand_depth: 0,
+    };
+
+    let err = execute_program(
+        &database,
+        parse("Range 1000000000 do @users:1 \"n\" it Set Drop End").unwrap(),
+        Budget::new(limits(1000, 0)),
+    )
+    .unwrap_err();
+    assert_eq!(err.code(), "query_aborted");
+    assert_eq!(
+        err.message(),
+        "Query exceeded its budget of 1000 instructions"
+    );
+    // The writes made before the limit was hit are rolled back
+    assert_eq!(database.get(&RecordId::new("users", 1)), None);
+
+    let start = Instant::now();
+    let err = database
+        .query_with_limits("Range 1000000000 do 1 Drop End", limits(0, 50))
+        .unwrap_err();
+    assert_eq!(err.message(), "Query timed out after 50ms");
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    assert!(database
+        .query_with_limits("Range 10 do 1 Drop End", limits(100, 1000))
+        .is_ok());
+
+    let expand = |depth: u64| Limits {
+        max_expand_depth: depth,
+        ..Limits::default()
+    };
+    assert!(database.query_with_limits("2 Expand", expand(2)).is_ok());
+    let err = database
+        .query_with_limits("3 Expand", expand(2))
+        .unwrap_err();
+    assert_eq!(err.message(), "Expand depth 3 is above the limit of 2");
+
+    // Waiting for a table another query holds counts against the timeout
+    query(&database, "@users:2 \"n\" 1 Set").unwrap();
+    {
+        let users = Arc::clone(&database.tables.read().unwrap()["users"]);
+        let _writer = users.writer.lock().unwrap();
+        let err = database
+            .query_with_limits("@users:2 \"n\" 2 Set", limits(0, 50))
+            .unwrap_err();
+        assert_eq!(err.message(), "Query timed out after 50ms");
+        assert_eq!(err.status(), 408);
+    }
+    let record = database.get(&RecordId::new("users", 2)).unwrap();
+    assert_eq!(record.get("n"), Some(&Value::Int(1)));
+
+    // Headers may only make the server's limits stricter
+    let request = |headers: &[(&str, &str)]| http::Request {
+        method: "POST".to_owned(),
+        path: "/".to_owned(),
+        headers: headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        body: Vec::new(),
+    };
+    let server = limits(1000, 30_000);
+    assert_eq!(request_limits(&request(&[]), server).unwrap(), server);
+    assert_eq!(
+        request_limits(
+            &request(&[("x-max-instructions", "5000"), ("x-query-timeout", "2")]),
+            server
+        )
+        .unwrap(),
+        limits(1000, 2000)
+    );
+    assert_eq!(
+        request_limits(&request(&[("x-max-instructions", "10")]), limits(0, 0)).unwrap(),
+        limits(10, 0)
+    );
+    assert!(matches!(
+        request_limits(&request(&[("x-query-timeout", "soon")]), server),
+        Err(Error::BadRequest(_))
+    ));
+}
+
+#[test]
+fn cancel_queries() {
+    let database = memory();
+    let registry = Arc::new(Registry::default());
+
+    let (sender, receiver) = mpsc::channel();
+    let thread = {
+        let database = Arc::clone(&database);
+        let registry = Arc::clone(&registry);
+        thread::spawn(move || {
+            let registration = registry.register("Range 1000000000 do\n 1 Drop End\n");
+            sender.send(registration.id).unwrap();
+
+            let mut budget = Budget::new(Limits::default());
+            budget.cancelled = Some(Arc::clone(&registration.cancelled));
+            let program = parse("Range 1000000000 do @users:1 \"n\" it Set Drop End").unwrap();
+            execute_program(&database, program, budget).map(|_| ())
+        })
+    };
+    let id = receiver.recv().unwrap();
+
+    let request = |method: &str, path: &str| http::Request {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        headers: Vec::new(),
+        body: Vec::new(),
+    };
+
+    let listed = registry.list();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].id, id);
+    assert_eq!(listed[0].query, "Range 1000000000 do\n 1 Drop End");
+    let json = manage_queries(&request("GET", "/queries"), &registry).unwrap();
+    assert!(json.contains(&format!("\"id\":{},", id)));
+
+    manage_queries(&request("DELETE", &format!("/queries/{}", id)), &registry).unwrap();
+    let err = thread.join().unwrap().unwrap_err();
+    assert_eq!(err.message(), "Query was cancelled");
+    assert_eq!(database.get(&RecordId::new("users", 1)), None);
+
+    // Finished queries are no longer listed
+    assert!(registry.list().is_empty());
+    let err = manage_queries(&request("DELETE", &format!("/queries/{}", id)), &registry);
+    assert_eq!(err.unwrap_err().status(), 404);
+    assert!(matches!(
+        manage_queries(&request("DELETE", "/queries/abc"), &registry),
+        Err(Error::BadRequest(_))
+    ));
+    assert!(matches!(
+        manage_queries(&request("POST", "/queries"), &registry),
+        Err(Error::BadRequest(_))
+    ));
+
+    // Shutting down cancels whatever is still running
+    let first = registry.register("1");
+    let second = registry.register("2");
+    assert_eq!(registry.cancel_all(), 2);
+    assert!(first.cancelled.load(Ordering::SeqCst));
+    assert!(second.cancelled.load(Ordering::SeqCst));
+}
+
+#[test]
+fn manage_tables() {
+    let database = memory();
+
+    query(
+        &database,
+        "@users:1 \"age\" 22 Set @users:2 \"age\" 23 Set @orders:1 \"total\" 5 Set",
+    )
+    .unwrap();
+
+    query(
+        &database,
+        "\"users\" \"people\" Rename_Table \"orders\" Drop_Table",
+    )
+    .unwrap();
+
+    let result = query(&database, "Tables").unwrap();
+    assert_eq!(
+        result.items,
+        vec![
+            ResultItem::Table {
+                name: "0".to_owned(),
+                rows: 0
+            },
+            ResultItem::Table {
+                name: "people".to_owned(),
+                rows: 2
+            }
+        ]
+    );
+
+    let result = query(&database, "@people:1 Select").unwrap();
+    assert_eq!(result.items.len(), 1);
+    assert_eq!(
+        table(&database, "people", |table| fields(table, 1)["id"].clone()),
+        Value::Id(RecordId {
+            table_name: "people".to_owned(),
+            row: 1
+        })
+    );
+
+    assert!(query(&database, "\"orders\" Drop_Table").is_err());
+    assert!(query(
+        &database,
+        "@pets:1 \"a\" 1 Set Drop \"pets\" \"people\" Rename_Table"
+    )
+    .is_err());
+}
+
+#[test]
+fn arrays() {
+    let database = memory();
+
+    query(
+        &database,
+        "@users:1 \"tags\" [\"a\" \"b\"] Set \"scores\" [1 [2 3]] Set Drop
+         @users:2 \"tags\" [\"c\"] \"d\" Append Set Drop",
+    )
+    .unwrap();
+
+    table(&database, "users", |table| {
+        assert_eq!(
+            fields(table, 1)["scores"],
+            Value::Array(vec![
+                Value::Int(1),
+                Value::Array(vec![Value::Int(2), Value::Int(3)])
+            ])
+        );
+        assert_eq!(
+            fields(table, 2)["tags"],
+            Value::Array(vec![
+                Value::String("c".to_owned()),
+                Value::String("d".to_owned())
+            ])
+        );
+    });
+
+    let result = query(&database, "@users:_ \"tags\" \"d\" \"contains\" Filter").unwrap();
+    assert_eq!(
+        result.items,
+        vec![ResultItem::Record(RecordId {
+            table_name: "users".to_owned(),
+            row: 2
+        })]
+    );
+
+    query(
+        &database,
+        "@users:3 \"n\" [4 5 6] 2 Index Set \"m\" [4 5 6] Len Set \"p\" [4 5 6] Pop Drop Set",
+    )
+    .unwrap();
+    table(&database, "users", |table| {
+        let fields = fields(table, 3);
+        assert_eq!(fields["n"], Value::Int(6));
+        assert_eq!(fields["m"], Value::Int(3));
+        assert_eq!(
+            fields["p"],
+            Value::Array(vec![Value::Int(4), Value::Int(5)])
+        );
+    });
+
+    assert!(query(&database, "[1 2] 2 Index").is_err());
+    assert!(query(&database, "[] Pop").is_err());
+    assert!(parse("[1 Set]").is_err());
+    assert!(parse("[1 2").is_err());
+}
+
+#[test]
+fn expand_record_links() {
+    let database = memory();
+
+    query(
+        &database,
+        "@customers:1 \"name\" \"x\" Set \"last_order\" @orders:1 Link Set Drop
+         @orders:1 \"customer\" @customers:1 Link Set \"items\" [] @items:1 Link Append Set Drop
+         @items:1 \"price\" 5 Set Drop",
+    )
+    .unwrap();
+
+    let result = query(&database, "@orders:1 Select").unwrap();
+    let json = results_to_json(&database, result);
+    assert!(json.contains("\"customer\":\"@customers:1\""));
+
+    let result = query(&database, "2 Expand @orders:1 Select").unwrap();
+    let json = results_to_json(&database, result);
+    assert!(json.contains("\"name\":\"x\""));
+    assert!(json.contains("\"price\":5"));
+    // The link back to the order is not expanded again
+    assert!(json.contains("\"last_order\":\"@orders:1\""));
+
+    assert!(query(&database, "-1 Expand").is_err());
+}
+
+#[test]
+fn bool_and_null() {
+    let database = memory();
+
+    query(
+        &database,
+        "@users:1 \"admin\" true Set \"manager\" null Set Drop
+         @users:2 \"admin\" FALSE Set \"manager\" @users:1 Set Drop",
+    )
+    .unwrap();
+
+    let result = query(&database, "@users:_ \"admin\" true \"==\" Filter").unwrap();
+    assert_eq!(result.items.len(), 1);
+
+    let result = query(&database, "@users:_ \"manager\" null \"==\" Filter").unwrap();
+    assert_eq!(result.items.len(), 1);
+
+    let result = query(&database, "1 Expand @users:1 Select").unwrap();
+    let json = results_to_json(&database, result);
+    assert!(json.contains("\"admin\":true"));
+    assert!(json.contains("\"manager\":null"));
+}
+
+#[test]
+fn value_ordering() {
+    assert_eq!(Value::Int(1), Value::Float(1.0));
+    assert_eq!(Value::Int(0), Value::Float(-0.0));
+    assert_eq!(Value::Float(f64::NAN), Value::Float(f64::NAN));
+    assert!(Value::Int(1) < Value::Float(1.5));
+    assert!(Value::Float(-2.5) < Value::Int(-2));
+    assert!(Value::Int(i64::MAX) < Value::Float(1e19));
+    assert!(Value::Float(f64::INFINITY) < Value::Float(f64::NAN));
+
+    assert!(Value::Null < Value::Bool(false));
+    assert!(Value::Bool(false) < Value::Bool(true));
+    assert!(Value::Bool(true) < Value::Int(i64::MIN));
+    assert!(Value::Float(f64::NAN) < Value::String(String::new()));
+    assert_ne!(Value::Null, Value::Int(0));
+}
+
+#[test]
+fn hash_index() {
+    let database = memory();
+
+    query(
+        &database,
+        "@users:1 \"age\" 22 Set Drop @users:2 \"age\" 23 Set Drop \"users\" \"Age\" Create_Index
+         @users:3 \"age\" 22.0 Set Drop @users:2 \"age\" 22 Set Drop @users:1 \"age\" Unset Drop",
+    )
+    .unwrap();
+
+    let rows = |database: &DatabaseRef| {
+        let result = query(database, "@users:_ \"age\" 22 \"==\" Filter").unwrap();
+        let mut rows: Vec<_> = result
+            .items
+            .iter()
+            .map(|item| match item {
+                ResultItem::Record(record_id) => record_id.row,
+                _ => unreachable!(),
+            })
+            .collect();
+        rows.sort();
+        rows
+    };
+    assert_eq!(rows(&database), vec![2, 3]);
+
+    query(&database, "@users:3 Delete").unwrap();
+    assert_eq!(rows(&database), vec![2]);
+
+    query(
+        &database,
+        "\"users\" \"people\" Rename_Table \"people\" \"users\" Rename_Table",
+    )
+    .unwrap();
+    assert_eq!(rows(&database), vec![2]);
+
+    assert!(query(&database, "\"users\" \"age\" Create_Index").is_err());
+}
+
+#[test]
+fn index_survives_snapshot() {
+    let dir = temp_dir("index_snapshot");
+
+    {
+        let database = open(&dir);
+        query(
+            &database,
+            "@users:1 \"age\" 22 Set Drop \"users\" \"age\" Create_Index",
+        )
+        .unwrap();
+    }
+    {
+        let database = open(&dir);
+        assert!(table(&database, "users", |table| table
+            .indexes
+            .contains_key("age")));
+        query(&database, "Snapshot").unwrap();
+    }
+
+    let database = open(&dir);
+    let result = query(&database, "@users:_ \"age\" 22 \"==\" Filter").unwrap();
+    assert_eq!(result.items.len(), 1);
+    assert!(table(&database, "users", |table| table
+        .indexes
+        .contains_key("age")));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn ordered_index() {
+    let database = memory();
+
+    query(
+        &database,
+        "@users:1 \"age\" 30 Set Drop @users:2 \"age\" 20 Set Drop @users:3 \"age\" 25.5 Set Drop
+         @users:4 \"name\" \"x\" Set Drop",
+    )
+    .unwrap();
+
+    let rows = |database: &DatabaseRef, contents: &str| -> Vec<u64> {
+        query(database, contents)
+            .unwrap()
+            .items
+            .iter()
+            .map(|item| match item {
+                ResultItem::Record(record_id) => record_id.row,
+                _ => unreachable!(),
+            })
+            .collect()
+    };
+
+    let sorted = "@users:_ \"age\" Order_By";
+    assert_eq!(rows(&database, sorted), vec![2, 3, 1]);
+
+    query(&database, "\"users\" \"age\" Create_Ordered_Index").unwrap();
+    assert_eq!(rows(&database, sorted), vec![2, 3, 1]);
+
+    let mut less = rows(&database, "@users:_ \"age\" 30 \"<\" Filter");
+    less.sort();
+    assert_eq!(less, vec![2, 3]);
+    assert_eq!(
+        rows(&database, "@users:_ \"age\" 25.5 \">=\" Filter").len(),
+        2
+    );
+    assert_eq!(
+        rows(&database, "@users:_ \"age\" 30.0 \"==\" Filter"),
+        vec![1]
+    );
+
+    query(&database, "@users:2 \"age\" 40 Set").unwrap();
+    assert_eq!(rows(&database, sorted), vec![3, 1, 2]);
+}
+
+#[test]
+fn http_content_length() {
+    let mut raw: &[u8] =
+        b"POST /query HTTP/1.1\r\nHost: x\r\nContent-Length: 26\r\n\r\n@users:1 \"a;b\" \"x y\" Set ;";
+    let request = http::read_request(&mut raw, 1024).unwrap();
+
+    assert_eq!(request.method, "POST");
+    assert_eq!(request.path, "/query");
+    assert_eq!(request.header("host"), Some("x"));
+    assert_eq!(request.body, b"@users:1 \"a;b\" \"x y\" Set ;");
+}
+
+#[test]
+fn http_chunked() {
+    let mut raw: &[u8] =
+        b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nTabl\r\n2;ext=1\r\nes\r\n0\r\n\r\n";
+    let request = http::read_request(&mut raw, 1024).unwrap();
+    assert_eq!(request.body, b"Tables");
+
+    let mut raw: &[u8] =
+        b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nTabl\r\n4\r\nes\r\n";
+    assert_eq!(
+        http::read_request(&mut raw, 1024)
+            .map_err(Error::from)
+            .unwrap_err()
+            .status(),
+        400
+    );
+}
+
+#[test]
+fn http_errors() {
+    let status = |mut raw: &[u8], max| {
+        http::read_request(&mut raw, max)
+            .map_err(Error::from)
+            .unwrap_err()
+            .status()
+    };
+
+    assert_eq!(
+        status(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nTables", 4),
+        413
+    );
+    assert_eq!(
+        status(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nTable\r\n",
+            4
+        ),
+        413
+    );
+    assert_eq!(
+        status(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nT\r\nffffffffffffffff\r\n",
+            64
+        ),
+        413
+    );
+    assert_eq!(
+        status(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nTables", 64),
+        400
+    );
+    assert_eq!(
+        status(b"POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n", 64),
+        400
+    );
+    assert_eq!(status(b"POST /\r\n\r\n", 64), 400);
+    assert_eq!(status(b"POST / HTTP/1.1\r\nHost\r\n\r\n", 64), 400);
+    assert_eq!(
+        http::read_request(&mut &b""[..], 64).unwrap_err(),
+        http::HttpError::Closed
+    );
+}
+
+#[test]
+fn error_status_and_json() {
+    let database = memory();
+
+    let err = parse("@users:1 \"a\" 1 Set\n  Foo").unwrap_err();
+    assert_eq!(err.status(), 400);
+    assert_eq!(err.position(), Some((2, 3)));
+
+    let err = query(&database, "@users:1 Select").unwrap_err();
+    assert!(matches!(&err, Error::TableNotFound { table_name, .. } if table_name == "users"));
+    assert_eq!(err.status(), 404);
+
+    let err = query(&database, "@users:1 \"a\" 1 Set @users:2 Select").unwrap_err();
+    assert!(matches!(err, Error::RecordNotFound { .. }));
+    assert_eq!(err.code(), "record_not_found");
+
+    let err = query(&database, "1 Link").unwrap_err();
+    assert!(matches!(err, Error::TypeError { .. }));
+    assert_eq!(err.status(), 400);
+
+    let err = query(&database, "1 Set").unwrap_err();
+    assert!(matches!(
+        err,
+        Error::StackUnderflow {
+            needed: 3,
+            found: 1,
+            ..
+        }
+    ));
+
+    let err = Error::io(
+        "Unable to open storage",
+        std::io::ErrorKind::NotFound.into(),
+    );
+    assert_eq!(err.status(), 500);
+    assert!(std::error::Error::source(&err).is_some());
+
+    assert!(parse("Range 3 do It").is_err());
+    assert!(parse("Range").is_err());
+
+    let err = Error::query("a \"quoted\"\nmessage".to_owned());
+    assert_eq!(
+        error_to_json(&err, None),
+        "{\"message\":\"a \\\"quoted\\\"\\nmessage\",\"code\":\"query_error\"}"
+    );
+}
+
+#[test]
+fn runtime_error_spans() {
+    let database = memory();
+
+    let contents = "Range 3 do\n  @t:1 \"a\" It Set Drop\n  \"b\" 1  Set\nend";
+    let err = query(&database, contents).unwrap_err();
+    assert_eq!(
+        err.span(),
+        Some(Span {
+            line: 3,
+            col: 10,
+            len: 3
+        })
+    );
+    assert_eq!(
+        err.snippet(contents).unwrap(),
+        "3 |   \"b\" 1  Set\n  |          ^^^"
+    );
+
+    let err = query(&database, "@missing:1 Select").unwrap_err();
+    assert_eq!(err.position(), Some((1, 12)));
+
+    // The span covers the whole string literal
+    let err = parse("\"é\\\"\" Foo").unwrap_err();
+    assert_eq!(err.span().unwrap().col, 7);
+    let err = query(&database, "\"é\\\"\" Link").unwrap_err();
+    assert_eq!(
+        err.snippet("\"é\\\"\" Link").unwrap(),
+        "1 | \"é\\\"\" Link\n  |       ^^^^"
+    );
+}
+
+// Minimal JSON reader used to check the writer output round trips
+#[derive(Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+fn read_json(input: &str) -> Json {
+    fn skip(chars: &[char], pos: &mut usize) {
+        while *pos < chars.len() && chars[*pos].is_whitespace() {
+            *pos += 1;
+        }
+    }
+
+    fn string(chars: &[char], pos: &mut usize) -> String {
+        assert_eq!(chars[*pos], '"');
+        *pos += 1;
+        let mut output = String::new();
+        loop {
+            let ch = chars[*pos];
+            *pos += 1;
+            match ch {
+                '"' => return output,
+                '\\' => {
+                    let ch = chars[*pos];
+                    *pos += 1;
+                    output.push(match ch {
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'b' => '\u{08}',
+                        'f' => '\u{0c}',
+                        'u' => {
+                            let hex: String = chars[*pos..*pos + 4].iter().collect();
+                            *pos += 4;
+                            char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap()
+                        }
+                        ch => ch,
+                    });
+                }
+                ch => {
+                    assert!(ch as u32 >= 0x20, "unescaped control character");
+                    output.push(ch);
+                }
+            }
+        }
+    }
+
+    fn value(chars: &[char], pos: &mut usize) -> Json {
+        skip(chars, pos);
+        let rest: String = chars[*pos..].iter().take(5).collect();
+        match chars[*pos] {
+            '{' | '[' => {
+                let object = chars[*pos] == '{';
+                *pos += 1;
+                let mut fields = Vec::new();
+                let mut values = Vec::new();
+                loop {
+                    skip(chars, pos);
+                    if chars[*pos] == '}' || chars[*pos] == ']' {
+                        *pos += 1;
+                        break;
+                    }
+                    if object {
+                        let key = string(chars, pos);
+                        skip(chars, pos);
+                        assert_eq!(chars[*pos], ':');
+                        *pos += 1;
+                        fields.push((key, value(chars, pos)));
+                    } else {
+                        values.push(value(chars, pos));
+                    }
+                    skip(chars, pos);
+                    if chars[*pos] == ',' {
+                        *pos += 1;
+                    }
+                }
+                if object {
+                    Json::Object(fields)
+                } else {
+                    Json::Array(values)
+                }
+            }
+            '"' => Json::String(string(chars, pos)),
+            _ if rest.starts_with("null") => {
+                *pos += 4;
+                Json::Null
+            }
+            _ if rest.starts_with("true") => {
+                *pos += 4;
+                Json::Bool(true)
+            }
+            _ if rest.starts_with("false") => {
+                *pos += 5;
+                Json::Bool(false)
+            }
+            _ => {
+                let start = *pos;
+                while *pos < chars.len() && "+-.eE0123456789".contains(chars[*pos]) {
+                    *pos += 1;
+                }
+                let number: String = chars[start..*pos].iter().collect();
+                Json::Number(number.parse().unwrap())
+            }
+        }
+    }
+
+    let chars: Vec<char> = input.chars().collect();
+    let mut pos = 0;
+    let json = value(&chars, &mut pos);
+    skip(&chars, &mut pos);
+    assert_eq!(pos, chars.len(), "trailing characters");
+    json
+}
+
+#[test]
+fn json_round_trip() {
+    let strings = [
+        "plain",
+        "",
+        "quote \" backslash \\ slash /",
+        "lines\n\r\ttabs",
+        "control \u{01}\u{08}\u{0c}\u{1f}",
+        "unicode é 日本 🦀",
+    ];
+
+    for str in strings {
+        let mut json = JsonWriter::new();
+        json.begin_object();
+        json.key(str);
+        json.string(str);
+        json.end_object();
+
+        assert_eq!(
+            read_json(&json.finish()),
+            Json::Object(vec![(str.to_owned(), Json::String(str.to_owned()))])
+        );
+    }
+
+    let mut json = JsonWriter::new();
+    json.begin_array();
+    json.float(f64::NAN);
+    json.float(f64::INFINITY);
+    json.float(1.5e300);
+    json.float(-0.25);
+    json.int(i64::MIN);
+    json.begin_array();
+    json.end_array();
+    json.begin_object();
+    json.end_object();
+    json.bool(false);
+    json.end_array();
+
+    assert_eq!(
+        read_json(&json.finish()),
+        Json::Array(vec![
+            Json::Null,
+            Json::Null,
+            Json::Number(1.5e300),
+            Json::Number(-0.25),
+            Json::Number(i64::MIN as f64),
+            Json::Array(vec![]),
+            Json::Object(vec![]),
+            Json::Bool(false),
+        ])
+    );
+}
+
+#[test]
+fn results_json_is_valid() {
+    let database = memory();
+
+    let json = results_to_json(&database, QueryResult::default());
+    assert_eq!(
+        read_json(&json),
+        Json::Object(vec![
+            ("message".to_owned(), Json::String("OK".to_owned())),
+            ("data".to_owned(), Json::Array(vec![]))
+        ])
+    );
+
+    query(
+        &database,
+        "@users:1 \"name\" [\"a\" 1.5 null true] Set \"inf\" 1e400 Set Drop",
+    )
+    .unwrap();
+
+    let result = query(&database, "@users:1 Select Tables").unwrap();
+    let json = read_json(&results_to_json(&database, result));
+
+    let data = match json {
+        Json::Object(fields) => fields.into_iter().find(|(key, _)| key == "data").unwrap().1,
+        _ => unreachable!(),
+    };
+    let items = match data {
+        Json::Array(items) => items,
+        _ => unreachable!(),
+    };
+    assert_eq!(items.len(), 3);
+
+    let record = match &items[0] {
+        Json::Object(fields) => fields,
+        _ => unreachable!(),
+    };
+    let field = |key: &str| &record.iter().find(|(k, _)| k == key).unwrap().1;
+    assert_eq!(field("inf"), &Json::Null);
+    assert_eq!(
+        field("name"),
+        &Json::Array(vec![
+            Json::String("a".to_owned()),
+            Json::Number(1.5),
+            Json::Null,
+            Json::Bool(true)
+        ])
+    );
+
+    let err = read_json(&error_to_json(
+        &Error::ParseError {
+            message: "bad \"word\"\n".to_owned(),
+            span: Span {
+                line: 1,
+                col: 2,
+                len: 3,
+            },
+        },
+        Some("\tfoo"),
+    ));
+    assert_eq!(
+        err,
+        Json::Object(vec![
+            (
+                "message".to_owned(),
+                Json::String("bad \"word\"\n".to_owned())
+            ),
+            ("code".to_owned(), Json::String("parse_error".to_owned())),
+            ("line".to_owned(), Json::Number(1.0)),
+            ("column".to_owned(), Json::Number(2.0)),
+            (
+                "snippet".to_owned(),
+                Json::String("1 | \tfoo\n  | \t^^^".to_owned())
+            ),
+        ])
+    );
+}
+
+#[test]
+fn embedded_api() {
+    let dir = temp_dir("embedded_api");
+    let config = Config {
+        data_dir: Some(dir.clone()),
+    };
+
+    {
+        let database = Database::open(config.clone()).unwrap();
+        let id = RecordId::new("users", 1);
+
+        database.set(&id, "Name", "ayush").unwrap();
+        database.set(&id, "age", 22).unwrap();
+        assert_eq!(
+            database.get(&id).unwrap().get("name"),
+            Some(&Value::from("ayush"))
+        );
+
+        database.set(&RecordId::new("users", 2), "age", 30).unwrap();
+        database.delete(&RecordId::new("users", 2)).unwrap();
+        assert!(matches!(
+            database.delete(&RecordId::new("users", 2)),
+            Err(Error::RecordNotFound { .. })
+        ));
+
+        let output = database
+            .query("@users:1 Select \"0\" Drop_Table Tables")
+            .unwrap();
+        assert_eq!(output.items.len(), 2);
+        match &output.items[0] {
+            OutputItem::Record { id, record } => {
+                assert_eq!(id.table_name(), "users");
+                assert_eq!(id.row(), 1);
+                assert_eq!(record.get("age"), Some(&Value::Int(22)));
+            }
+            item => panic!("unexpected item {:?}", item),
+        }
+        assert_eq!(
+            output.items[1],
+            OutputItem::Table {
+                name: "users".to_owned(),
+                rows: 1
+            }
+        );
+    }
+
+    let database = Database::open(config).unwrap();
+    let record = database.get(&RecordId::new("users", 1)).unwrap();
+    assert_eq!(record.fields().len(), 3);
+    assert!(database.get(&RecordId::new("users", 2)).is_none());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn server_config() {
+    let args = |args: &[&str]| ServerConfig::from_args(args.iter().map(|arg| arg.to_string()));
+
+    assert_eq!(args(&[]).unwrap(), ServerConfig::default());
+
+    let dir = temp_dir("server_config");
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("real_db.toml");
+    std::fs::write(
+        &file,
+        "# Server\nbind = \"0.0.0.0\"\nport = 8080 # http\nworkers=8\n\ndata_dir = \"/var/db #1\"\nlog_level = \"debug\"\n",
+    )
+    .unwrap();
+    let file = file.to_str().unwrap();
+
+    let config = args(&[
+        "--config",
+        file,
+        "--port=9000",
+        "--max-request-size",
+        "64",
+        "--query-timeout",
+        "5",
+    ])
+    .unwrap();
+    assert_eq!(config.bind.to_string(), "0.0.0.0");
+    assert_eq!(config.port, 9000);
+    assert_eq!(config.workers, 8);
+    assert_eq!(config.data_dir, PathBuf::from("/var/db #1"));
+    assert_eq!(config.max_request_size, 64);
+    assert_eq!(config.snapshot_interval, 300);
+    assert_eq!(config.log_level, LogLevel::Debug);
+    assert_eq!(
+        config.limits(),
+        Limits {
+            max_instructions: 100_000_000,
+            timeout: Duration::from_secs(5),
+            max_expand_depth: 32,
+        }
+    );
+
+    let err = |list: &[&str]| args(list).unwrap_err().to_string();
+    assert_eq!(
+        err(&["--port", "70000"]),
+        "--port: Invalid value `70000` for port"
+    );
+    assert_eq!(err(&["--workers", "0"]), "workers must be at least 1");
+    assert_eq!(
+        err(&["--colour", "red"]),
+        "--colour: Unknown setting `colour`"
+    );
+    assert_eq!(err(&["--bind"]), "Missing value for --bind");
+    assert_eq!(err(&["8080"]), "Unexpected argument `8080`");
+    assert!(matches!(
+        args(&["--config", "/nonexistent/real_db.toml"]),
+        Err(Error::Io { .. })
+    ));
+
+    std::fs::write(dir.join("bad.toml"), "port = 1\nlog_level = loud\n").unwrap();
+    let bad = dir.join("bad.toml");
+    assert!(err(&["--config", bad.to_str().unwrap()]).ends_with(
+        "bad.toml: line 2: Unknown log level `loud`, expected off, error, warn, info or debug"
+    ));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn thread_pool_shutdown() {
+    let done = Arc::new(AtomicUsize::new(0));
+
+    // Queued jobs still run before the workers stop
+    let pool = ThreadPool::new(2);
+    for _ in 0..6 {
+        let done = Arc::clone(&done);
+        pool.execute(move || {
+            thread::sleep(Duration::from_millis(20));
+            done.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    assert!(pool.shutdown(Duration::from_secs(5)));
+    assert_eq!(done.load(Ordering::SeqCst), 6);
+
+    // Every busy worker is left behind, not only the first one
+    let pool = ThreadPool::new(3);
+    for _ in 0..3 {
+        pool.execute(|| thread::sleep(Duration::from_secs(1)));
+    }
+    let start = Instant::now();
+    assert!(!pool.shutdown(Duration::from_millis(50)));
+    assert!(start.elapsed() < Duration::from_millis(900));
+}
+
+#[test]
+fn string_literals() {
+    let database = memory();
+
+    query(
+        &database,
+        "@users:1 \"name\" \"a \\\"quoted\\\" # not a comment; \\\\ \\n\\t\\u{1F980}\" Set # comment \"\n\"x\"\"y\" Set",
+    )
+    .unwrap();
+
+    let record = database.get(&RecordId::new("users", 1)).unwrap();
+    let fields = record.fields();
+    assert_eq!(
+        fields["name"],
+        Value::String("a \"quoted\" # not a comment; \\ \n\t🦀".to_owned())
+    );
+    assert_eq!(fields["x"], Value::String("y".to_owned()));
+}
+
+#[test]
+fn lexer_errors() {
+    let position = |contents: &str| parse(contents).unwrap_err().position();
+
+    assert_eq!(position("Tables\n  \"open"), Some((2, 3)));
+    assert_eq!(position("\"a\\qb\""), Some((1, 3)));
+    assert_eq!(position("\"\\u{110000}\""), Some((1, 2)));
+    assert_eq!(position("\"\\u1234\""), Some((1, 2)));
+    // Columns count characters, not bytes
+    assert_eq!(position("\"日本\" Foo"), Some((1, 6)));
+    assert_eq!(position("[1 2]Set;Foo"), Some((1, 10)));
+}
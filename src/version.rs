@@ -0,0 +1,115 @@
+use crate::{Record, Value};
+
+// Timestamp of versions written by a transaction that hasn't
+// committed yet. Writers read at PENDING to see their own writes
+pub const PENDING: u64 = u64::MAX;
+
+struct Version {
+    // Timestamp of the commit that wrote it
+    ts: u64,
+    // None once the record is deleted
+    record: Option<Record>,
+}
+
+// Versions of one record that some program may still read, oldest first
+#[derive(Default)]
+pub struct Versions(Vec<Version>);
+
+impl Versions {
+    // A record loaded from storage, visible to every program
+    pub fn new(record: Record) -> Versions {
+        Versions(vec![Version {
+            ts: 0,
+            record: Some(record),
+        }])
+    }
+
+    // The record as a program reading at `ts` sees it
+    pub fn get(&self, ts: u64) -> Option<&Record> {
+        self.0
+            .iter()
+            .rev()
+            .find(|version| version.ts <= ts)?
+            .record
+            .as_ref()
+    }
+
+    // The version the running transaction writes to,
+    // its first write starts from a copy of the newest one
+    pub fn pending(&mut self) -> &mut Option<Record> {
+        if self.0.last().is_none_or(|version| version.ts != PENDING) {
+            let record = self.0.last().and_then(|version| version.record.clone());
+            self.0.push(Version {
+                ts: PENDING,
+                record,
+            });
+        }
+        &mut self.0.last_mut().unwrap().record
+    }
+
+    // Removes the pending version, returning the record it held
+    pub fn discard(&mut self) -> Option<Record> {
+        match self.0.last() {
+            Some(version) if version.ts == PENDING => self.0.pop().unwrap().record,
+            _ => None,
+        }
+    }
+
+    // Makes the pending version visible to programs reading at `ts` or later
+    pub fn stamp(&mut self, ts: u64) {
+        if let Some(version) = self.0.last_mut().filter(|version| version.ts == PENDING) {
+            version.ts = ts;
+        }
+    }
+
+    // Drops the versions no program reading at `oldest` or later can see,
+    // returning the records they held
+    pub fn collect(&mut self, oldest: u64) -> Vec<Record> {
+        let keep = match self.0.iter().rposition(|version| version.ts <= oldest) {
+            Some(val) => val,
+            None => return Vec::new(),
+        };
+
+        let removed: Vec<_> = self
+            .0
+            .drain(..keep)
+            .filter_map(|version| version.record)
+            .collect();
+
+        // A deletion nothing older is left to hide reads the same as no version
+        if self.0[0].ts <= oldest && self.0[0].record.is_none() {
+            self.0.remove(0);
+        }
+        removed
+    }
+
+    // Whether a later `collect` may still drop some of the versions
+    pub fn is_collectable(&self) -> bool {
+        self.0.len() > 1
+            || self
+                .0
+                .first()
+                .is_some_and(|version| version.record.is_none())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Whether any version holds `value` for `key`
+    pub fn holds(&self, key: &str, value: &Value) -> bool {
+        self.records()
+            .any(|record| record.fields.get(key) == Some(value))
+    }
+
+    // Every version that isn't a deletion
+    pub fn records(&self) -> impl Iterator<Item = &Record> {
+        self.0.iter().filter_map(|version| version.record.as_ref())
+    }
+
+    pub fn records_mut(&mut self) -> impl Iterator<Item = &mut Record> {
+        self.0
+            .iter_mut()
+            .filter_map(|version| version.record.as_mut())
+    }
+}